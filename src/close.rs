@@ -1,4 +1,6 @@
-use libc::{__errno_location, fclose, FILE};
+use libc::{
+    __errno_location, c_char, c_int, c_uint, fclose, CLOSE_RANGE_CLOEXEC, CLOSE_RANGE_UNSHARE, FILE,
+};
 use std::fmt::Display;
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -34,6 +36,50 @@ impl Close {
         Ok(())
     }
 
+    /*
+     * close every fd in from..=to, or mark them close on exec with CLOSE_RANGE_CLOEXEC.
+     * use the close_range syscall, for kernel without it (or without CLOSE_RANGE_CLOEXEC)
+     * fall back to the fds listed in /proc/self/fd, then to every fd below RLIMIT_NOFILE.
+     * nothing is allocated, so it can be used in the child of fork
+     */
+    pub fn close_range(from: c_uint, to: c_uint, flags: c_uint) -> Result<(), Close> {
+        if from > to {
+            return Err(Close::CloseErrno(libc::EINVAL));
+        }
+        match unsafe { libc::syscall(libc::SYS_close_range, from, to, flags) } {
+            -1 => match unsafe { *__errno_location() } {
+                libc::ENOSYS | libc::EINVAL => close_range_fallback(from, to, flags),
+                v => Err(Close::CloseErrno(v)),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub fn closefrom(from: c_uint) -> Result<(), Close> {
+        Self::close_range(from, c_uint::MAX, 0)
+    }
+
+    // close every fd not in keep, keep is scanned instead of sorted so nothing is allocated
+    pub fn close_all_except(keep: &[c_int]) -> Result<(), Close> {
+        let mut from: c_uint = 0;
+        loop {
+            let next = keep
+                .iter()
+                .filter(|x| **x >= 0 && **x as c_uint >= from)
+                .min();
+            match next {
+                Some(fd) => {
+                    let fd = *fd as c_uint;
+                    if fd > from {
+                        Self::close_range(from, fd - 1, 0)?;
+                    }
+                    from = fd + 1;
+                }
+                None => return Self::close_range(from, c_uint::MAX, 0),
+            }
+        }
+    }
+
     pub fn fclose<'a>(ps: &'a [*mut FILE]) -> Result<(), Close> {
         unsafe {
             for i in ps {
@@ -48,12 +94,155 @@ impl Close {
     }
 }
 
+// close or mark close on exec, errors are ignored as the fd may be closed already
+fn close_one(fd: c_int, cloexec: bool) {
+    unsafe {
+        if cloexec {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        } else {
+            libc::close(fd);
+        }
+    }
+}
+
+fn close_range_fallback(from: c_uint, to: c_uint, flags: c_uint) -> Result<(), Close> {
+    if flags & CLOSE_RANGE_UNSHARE != 0 && unsafe { libc::unshare(libc::CLONE_FILES) } == -1 {
+        return Err(Close::CloseErrno(unsafe { *__errno_location() }));
+    }
+    let cloexec = flags & CLOSE_RANGE_CLOEXEC != 0;
+    let in_range = |fd: u64| fd >= from as u64 && fd <= to as u64;
+    let dir = unsafe {
+        libc::open(
+            "/proc/self/fd\0".as_ptr() as *const c_char,
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+    if dir == -1 {
+        // no /proc, try every fd could be opened
+        let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == -1 {
+            return Err(Close::CloseErrno(unsafe { *__errno_location() }));
+        }
+        let max = limit.rlim_cur.min(to as u64 + 1);
+        for fd in from as u64..max {
+            close_one(fd as c_int, cloexec);
+        }
+        return Ok(());
+    }
+    // linux_dirent64 records, aligned as d_ino is u64
+    let mut buf = [0u64; 512];
+    let result = loop {
+        let size = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                dir,
+                buf.as_mut_ptr(),
+                std::mem::size_of_val(&buf),
+            )
+        };
+        if size == -1 {
+            break Err(Close::CloseErrno(unsafe { *__errno_location() }));
+        }
+        if size == 0 {
+            break Ok(());
+        }
+        let records =
+            unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, size as usize) };
+        let mut offset = 0;
+        while offset < records.len() {
+            let record = &records[offset..];
+            let length = u16::from_ne_bytes([record[16], record[17]]) as usize;
+            // d_name start after d_ino, d_off, d_reclen and d_type, . and .. are skipped
+            let name = &record[19..length];
+            let mut fd: u64 = 0;
+            let mut digits = 0;
+            for c in name.iter().take_while(|x| **x != 0) {
+                if !c.is_ascii_digit() {
+                    digits = 0;
+                    break;
+                }
+                fd = fd * 10 + (*c - b'0') as u64;
+                digits += 1;
+            }
+            if digits != 0 && fd != dir as u64 && in_range(fd) {
+                close_one(fd as c_int, cloexec);
+            }
+            offset += length;
+        }
+    };
+    unsafe { libc::close(dir) };
+    result
+}
+
 #[cfg(test)]
 mod close {
-    use crate::{Close, Pipe, PipeSide};
+    use libc::{c_int, c_uint, CLOSE_RANGE_CLOEXEC};
+
+    use crate::{Close, Fork, Pipe, PipeSide};
 
     #[test]
     fn test_close_pipe() {
         Pipe::pipe().unwrap();
     }
+
+    // -1 when closed, or the fd flags
+    fn fd_flags(fd: c_int) -> c_int {
+        unsafe { libc::fcntl(fd, libc::F_GETFD) }
+    }
+
+    // the fd table is changed, so run f in a child as the test threads share the table
+    fn in_child<F: FnOnce() -> bool>(f: F) {
+        let child = Fork::spawn(|| if f() { 0 } else { 1 }).unwrap();
+        let (_, status) = child.wait().unwrap();
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+
+    fn fds() -> [c_int; 4] {
+        let mut fds = [0; 4];
+        unsafe {
+            libc::pipe(fds.as_mut_ptr());
+            libc::pipe(fds[2..].as_mut_ptr());
+        }
+        fds.sort_unstable();
+        fds
+    }
+
+    #[test]
+    fn test_close_range() {
+        in_child(|| {
+            let fds = fds();
+            Close::close_range(fds[1] as c_uint, fds[2] as c_uint, 0).unwrap();
+            Close::close_range(fds[3] as c_uint, fds[3] as c_uint, CLOSE_RANGE_CLOEXEC).unwrap();
+            fd_flags(fds[0]) == 0
+                && fd_flags(fds[1]) == -1
+                && fd_flags(fds[2]) == -1
+                && fd_flags(fds[3]) == libc::FD_CLOEXEC
+                && Close::close_range(1, 0, 0) == Err(Close::CloseErrno(libc::EINVAL))
+        });
+    }
+
+    #[test]
+    fn test_close_range_fallback() {
+        in_child(|| {
+            let fds = fds();
+            super::close_range_fallback(fds[0] as c_uint, fds[1] as c_uint, 0).unwrap();
+            super::close_range_fallback(fds[2] as c_uint, c_uint::MAX, CLOSE_RANGE_CLOEXEC)
+                .unwrap();
+            fd_flags(fds[0]) == -1
+                && fd_flags(fds[1]) == -1
+                && fd_flags(fds[2]) == libc::FD_CLOEXEC
+                && fd_flags(fds[3]) == libc::FD_CLOEXEC
+        });
+    }
+
+    #[test]
+    fn test_close_all_except() {
+        in_child(|| {
+            let fds = fds();
+            Close::close_all_except(&[fds[3], 2, fds[1], -1]).unwrap();
+            // nothing is allocated in the child of a multithread process
+            (0..=fds[3] + 1).all(|x| (fd_flags(x) != -1) == [2, fds[1], fds[3]].contains(&x))
+        });
+    }
 }
@@ -0,0 +1,350 @@
+use std::{
+    ffi::{CString, NulError},
+    fmt::Display,
+};
+
+use libc::{
+    __errno_location, c_int, c_void, mode_t, pid_t, O_APPEND, O_CREAT, O_RDONLY, O_RDWR, O_WRONLY,
+    STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO,
+};
+
+use crate::{errno_name, report::ReportPipe, Close, Dup, DupError, Fork, ForkError, ForkPid, Wait};
+
+/*
+ * daemonize current process:
+ * 1. fork, the caller stay in the parent and wait for the report
+ * 2. setsid in the child, then fork again so the grandchild can never get a controlling terminal
+ * 3. chdir, umask, redirect stdio in the grandchild
+ * 4. lock and write the pidfile, then report the pid to the caller
+ */
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Daemon {
+    pub chdir: String,
+    pub umask: mode_t,
+    pub stdin: Option<String>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    pub pidfile: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct DaemonReport {
+    pub pid: pid_t,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Daemonized {
+    // the caller, the daemon started successfully
+    Parent(DaemonReport),
+    // the detached grandchild
+    Daemon,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum DaemonStage {
+    Setsid,
+    Fork,
+    Chdir,
+    OpenStdio,
+    Dup2,
+    OpenPidfile,
+    LockPidfile,
+    WritePidfile,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DaemonError {
+    PipeCreateFailed(c_int),
+    ForkFailed(ForkError),
+    CStringParesError(NulError),
+    StartFailed(DaemonStage, c_int),
+    // the daemon exit before report anything
+    ReportMissed,
+}
+
+impl Display for DaemonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DaemonError::PipeCreateFailed(v) => {
+                f.write_str(std::format!("create report pipe failed! errno: {}", v).as_str())
+            }
+            DaemonError::ForkFailed(v) => f.write_str(std::format!("{}", v).as_str()),
+            DaemonError::CStringParesError(n) => {
+                f.write_str(std::format!("parse {:<.20} failed!", n.to_string()).as_str())
+            }
+            DaemonError::StartFailed(stage, v) => f.write_str(
+                std::format!(
+                    "daemon start failed at {:?}! errno: {} ({})",
+                    stage,
+                    v,
+                    errno_name(*v)
+                )
+                .as_str(),
+            ),
+            DaemonError::ReportMissed => f.write_str("daemon exit without report!"),
+        }
+    }
+}
+
+impl DaemonStage {
+    fn from_u32(v: u32) -> Option<DaemonStage> {
+        [
+            DaemonStage::Setsid,
+            DaemonStage::Fork,
+            DaemonStage::Chdir,
+            DaemonStage::OpenStdio,
+            DaemonStage::Dup2,
+            DaemonStage::OpenPidfile,
+            DaemonStage::LockPidfile,
+            DaemonStage::WritePidfile,
+        ]
+        .get(v as usize)
+        .copied()
+    }
+}
+
+// stage value of the success report, the value is the pid of the daemon
+const STARTED: u32 = u32::MAX;
+
+fn errno() -> c_int {
+    unsafe { *__errno_location() }
+}
+
+// not close on exec, open may return the stdio fd itself when it is closed, then no dup2 clear it
+fn open_or_null(path: &Option<CString>, null: &CString, flags: c_int) -> c_int {
+    let path = path.as_ref().unwrap_or(null);
+    unsafe { libc::open(path.as_ptr(), flags, 0o644) }
+}
+
+impl Default for Daemon {
+    fn default() -> Daemon {
+        Daemon {
+            chdir: String::from("/"),
+            umask: 0,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            pidfile: None,
+        }
+    }
+}
+
+impl Daemon {
+    pub fn new() -> Daemon {
+        Daemon::default()
+    }
+
+    pub fn chdir(mut self, path: &str) -> Daemon {
+        self.chdir = String::from(path);
+        self
+    }
+
+    pub fn umask(mut self, mask: mode_t) -> Daemon {
+        self.umask = mask;
+        self
+    }
+
+    // stdio not set is redirected to /dev/null, stdout and stderr files are opened for append
+    pub fn stdin(mut self, path: &str) -> Daemon {
+        self.stdin = Some(String::from(path));
+        self
+    }
+
+    pub fn stdout(mut self, path: &str) -> Daemon {
+        self.stdout = Some(String::from(path));
+        self
+    }
+
+    pub fn stderr(mut self, path: &str) -> Daemon {
+        self.stderr = Some(String::from(path));
+        self
+    }
+
+    // the pidfile is locked with fcntl for the lifetime of the daemon, also after it exec
+    pub fn pidfile(mut self, path: &str) -> Daemon {
+        self.pidfile = Some(String::from(path));
+        self
+    }
+
+    // call it before create any thread, the daemon only have the calling thread
+    pub fn daemonize(&self) -> Result<Daemonized, DaemonError> {
+        let to_cstring = |x: &Option<String>| -> Result<Option<CString>, DaemonError> {
+            x.as_ref()
+                .map(|v| CString::new(v.as_str()))
+                .transpose()
+                .map_err(DaemonError::CStringParesError)
+        };
+        let chdir = CString::new(self.chdir.as_str()).map_err(DaemonError::CStringParesError)?;
+        let null = CString::new("/dev/null").map_err(DaemonError::CStringParesError)?;
+        let stdin = to_cstring(&self.stdin)?;
+        let stdout = to_cstring(&self.stdout)?;
+        let stderr = to_cstring(&self.stderr)?;
+        let pidfile = to_cstring(&self.pidfile)?;
+        let mut report = ReportPipe::new().map_err(DaemonError::PipeCreateFailed)?;
+        match Fork::fork().map_err(DaemonError::ForkFailed)? {
+            ForkPid::Parent((_, child)) => {
+                report.close_write();
+                let message = report.recv();
+                while let Err(Wait::WaitFailure(libc::EINTR)) = Wait::children_with(child, 0) {}
+                match message {
+                    Ok(Some((STARTED, pid))) => Ok(Daemonized::Parent(DaemonReport { pid })),
+                    Ok(Some((stage, v))) => match DaemonStage::from_u32(stage) {
+                        Some(stage) => Err(DaemonError::StartFailed(stage, v)),
+                        None => Err(DaemonError::ReportMissed),
+                    },
+                    Ok(None) | Err(_) => Err(DaemonError::ReportMissed),
+                }
+            }
+            ForkPid::Children(_) => {
+                report.close_read();
+                let fail = |stage: DaemonStage, v: c_int| -> ! {
+                    report.send(stage as u32, v);
+                    unsafe { libc::_exit(1) }
+                };
+                if unsafe { libc::setsid() } == -1 {
+                    fail(DaemonStage::Setsid, errno());
+                }
+                match unsafe { libc::fork() } {
+                    -1 => fail(DaemonStage::Fork, errno()),
+                    0 => (),
+                    _ => unsafe { libc::_exit(0) },
+                }
+                if unsafe { libc::chdir(chdir.as_ptr()) } == -1 {
+                    fail(DaemonStage::Chdir, errno());
+                }
+                unsafe { libc::umask(self.umask) };
+                let fds = [
+                    open_or_null(&stdin, &null, O_RDONLY),
+                    open_or_null(&stdout, &null, O_WRONLY | O_CREAT | O_APPEND),
+                    open_or_null(&stderr, &null, O_WRONLY | O_CREAT | O_APPEND),
+                ];
+                if fds.contains(&-1) {
+                    fail(DaemonStage::OpenStdio, errno());
+                }
+                if let Err(DupError::Errno(v)) =
+                    Dup::dup2s(&fds, &[STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO])
+                {
+                    fail(DaemonStage::Dup2, v);
+                }
+                for fd in &fds {
+                    if *fd > STDERR_FILENO {
+                        Close::close(&[*fd]).ok();
+                    }
+                }
+                let pid = unsafe { libc::getpid() };
+                if let Some(path) = &pidfile {
+                    // the fd is leaked on purpose, close it will release the lock.
+                    // not close on exec, so the lock is kept when the daemon exec
+                    let fd = unsafe { libc::open(path.as_ptr(), O_RDWR | O_CREAT, 0o644) };
+                    if fd == -1 {
+                        fail(DaemonStage::OpenPidfile, errno());
+                    }
+                    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+                    lock.l_type = libc::F_WRLCK as libc::c_short;
+                    lock.l_whence = libc::SEEK_SET as libc::c_short;
+                    if unsafe { libc::fcntl(fd, libc::F_SETLK, &lock as *const libc::flock) } == -1
+                    {
+                        fail(DaemonStage::LockPidfile, errno());
+                    }
+                    let mut buf = [0u8; 16];
+                    let mut len = 0;
+                    let mut v = pid;
+                    while {
+                        buf[len] = b'0' + (v % 10) as u8;
+                        len += 1;
+                        v /= 10;
+                        v != 0
+                    } {}
+                    buf[..len].reverse();
+                    buf[len] = b'\n';
+                    len += 1;
+                    if unsafe { libc::ftruncate(fd, 0) } == -1
+                        || unsafe { libc::write(fd, buf.as_ptr() as *const c_void, len) }
+                            != len as isize
+                    {
+                        fail(DaemonStage::WritePidfile, errno());
+                    }
+                }
+                report.send(STARTED, pid);
+                report.close_write();
+                Ok(Daemonized::Daemon)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod daemon {
+    use std::{fs, os::unix::io::AsRawFd, thread::sleep, time::Duration};
+
+    use libc::c_void;
+
+    use crate::{temp_path, Daemon, DaemonError, DaemonStage, Daemonized};
+
+    #[test]
+    fn daemonize_with_pidfile() {
+        let pidfile = temp_path("daemon.pid");
+        let log = temp_path("daemon.log");
+        match Daemon::new()
+            .stdout(&log)
+            .pidfile(&pidfile)
+            .daemonize()
+            .unwrap()
+        {
+            Daemonized::Parent(report) => {
+                let mut content = String::new();
+                for _ in 0..100 {
+                    content = fs::read_to_string(&log).unwrap_or_default();
+                    if !content.is_empty() {
+                        break;
+                    }
+                    sleep(Duration::from_millis(20));
+                }
+                assert_eq!(content, "daemon\n");
+                let pid = fs::read_to_string(&pidfile).unwrap();
+                assert_eq!(pid, std::format!("{}\n", report.pid));
+                fs::remove_file(&pidfile).ok();
+                fs::remove_file(&log).ok();
+            }
+            Daemonized::Daemon => unsafe {
+                // the daemon is not the session leader, so it can never get a controlling terminal
+                // stdio survive the next exec
+                let inherited = (0..3).all(|fd| libc::fcntl(fd, libc::F_GETFD) == 0);
+                if libc::getsid(0) != libc::getpid() && inherited {
+                    libc::write(1, "daemon\n".as_ptr() as *const c_void, 7);
+                }
+                libc::_exit(0)
+            },
+        }
+    }
+
+    #[test]
+    fn daemonize_pidfile_locked() {
+        let pidfile = temp_path("locked.pid");
+        let file = fs::File::create(&pidfile).unwrap();
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = libc::F_WRLCK as libc::c_short;
+        lock.l_whence = libc::SEEK_SET as libc::c_short;
+        assert_ne!(
+            unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock as *const libc::flock) },
+            -1
+        );
+        match Daemon::new().pidfile(&pidfile).daemonize() {
+            Err(DaemonError::StartFailed(DaemonStage::LockPidfile, v)) => {
+                assert!(v == libc::EAGAIN || v == libc::EACCES)
+            }
+            Ok(Daemonized::Daemon) => unsafe { libc::_exit(0) },
+            other => panic!("{:?}", other),
+        }
+        fs::remove_file(&pidfile).ok();
+    }
+
+    #[test]
+    fn daemonize_chdir_failed() {
+        match Daemon::new().chdir("/libc_tools/not/exist").daemonize() {
+            Err(DaemonError::StartFailed(DaemonStage::Chdir, libc::ENOENT)) => (),
+            Ok(Daemonized::Daemon) => unsafe { libc::_exit(0) },
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    ffi::{CString, NulError, OsStr, OsString},
+    os::unix::ffi::OsStrExt,
+};
+
+/*
+ * environment of a child, resolved when the child is spawned:
+ * 1. inherit the current process, or nothing after clear
+ * 2. keep the inherited variables in the allowlist only, if any
+ * 3. set and remove in call order
+ * 4. secure strip the variables which change how the loader, libc or shell behave,
+ *    including the ones set by 3
+ */
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Env {
+    clear: bool,
+    allow: Option<Vec<OsString>>,
+    secure: bool,
+    // None value means remove
+    vars: Vec<(OsString, Option<OsString>)>,
+}
+
+// ignored by glibc for setuid programs, plus the startup files of shells
+const UNSECURE_VARS: &[&str] = &[
+    "BASH_ENV",
+    "CDPATH",
+    "ENV",
+    "GCONV_PATH",
+    "GETCONF_DIR",
+    "GLIBC_TUNABLES",
+    "HOSTALIASES",
+    "IFS",
+    "LOCALDOMAIN",
+    "LOCPATH",
+    "MALLOC_TRACE",
+    "NIS_PATH",
+    "NLSPATH",
+    "RESOLV_HOST_CONF",
+    "RES_OPTIONS",
+    "TMPDIR",
+    "TZDIR",
+];
+
+fn unsecure(key: &OsStr) -> bool {
+    key.as_bytes().starts_with(b"LD_") || UNSECURE_VARS.iter().any(|x| OsStr::new(x) == key)
+}
+
+impl Env {
+    pub fn new() -> Env {
+        Env::default()
+    }
+
+    pub fn clear(mut self) -> Env {
+        self.clear = true;
+        self
+    }
+
+    // inherited variables not in keys are dropped, variables set later are kept
+    pub fn allow(mut self, keys: &[&str]) -> Env {
+        self.allow
+            .get_or_insert_with(Vec::new)
+            .extend(keys.iter().map(OsString::from));
+        self
+    }
+
+    pub fn set(mut self, key: &str, value: &str) -> Env {
+        self.vars
+            .push((OsString::from(key), Some(OsString::from(value))));
+        self
+    }
+
+    pub fn remove(mut self, key: &str) -> Env {
+        self.vars.push((OsString::from(key), None));
+        self
+    }
+
+    // strip LD_PRELOAD, LD_LIBRARY_PATH and the like
+    pub fn secure(mut self) -> Env {
+        self.secure = true;
+        self
+    }
+
+    // the resolved variables, inherited ones keep the order of current environment
+    pub fn vars(&self) -> Vec<(OsString, OsString)> {
+        let mut vars: Vec<(OsString, OsString)> = if self.clear {
+            Vec::new()
+        } else {
+            std::env::vars_os()
+                .filter(|(key, _)| match &self.allow {
+                    Some(allow) => allow.contains(key),
+                    None => true,
+                })
+                .collect()
+        };
+        for (key, value) in &self.vars {
+            let index = vars.iter().position(|(k, _)| k == key);
+            match (index, value) {
+                (Some(i), Some(v)) => vars[i].1 = v.clone(),
+                (None, Some(v)) => vars.push((key.clone(), v.clone())),
+                (Some(i), None) => {
+                    vars.remove(i);
+                }
+                (None, None) => (),
+            }
+        }
+        if self.secure {
+            vars.retain(|(key, _)| !unsecure(key));
+        }
+        vars
+    }
+
+    // KEY=VALUE entries of envp, lowered before fork
+    pub fn entries(&self) -> Result<Vec<CString>, NulError> {
+        self.vars()
+            .into_iter()
+            .map(|(key, value)| {
+                let mut entry = Vec::with_capacity(key.len() + value.len() + 1);
+                entry.extend_from_slice(key.as_bytes());
+                entry.push(b'=');
+                entry.extend_from_slice(value.as_bytes());
+                CString::new(entry)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod env {
+    use std::ffi::{CString, OsString};
+
+    use crate::Env;
+
+    fn pairs(vars: &[(&str, &str)]) -> Vec<(OsString, OsString)> {
+        vars.iter()
+            .map(|(k, v)| (OsString::from(k), OsString::from(v)))
+            .collect()
+    }
+
+    #[test]
+    fn env_clear_set_remove() {
+        let env = Env::new()
+            .clear()
+            .set("A", "1")
+            .set("B", "2")
+            .set("A", "3")
+            .remove("B")
+            .remove("C");
+        assert_eq!(env.vars(), pairs(&[("A", "3")]));
+        assert_eq!(env.entries(), Ok(vec![CString::new("A=3").unwrap()]));
+        assert!(Env::new().clear().set("A", "a\0b").entries().is_err());
+    }
+
+    #[test]
+    fn env_inherit_allow() {
+        let path = std::env::var_os("PATH").unwrap();
+        let inherited = Env::new().vars();
+        assert!(inherited.contains(&(OsString::from("PATH"), path.clone())));
+        let env = Env::new().allow(&["PATH"]).set("A", "1");
+        assert_eq!(
+            env.vars(),
+            vec![
+                (OsString::from("PATH"), path),
+                (OsString::from("A"), OsString::from("1"))
+            ]
+        );
+        let env = Env::new().remove("PATH");
+        assert!(env.vars().iter().all(|(k, _)| k != "PATH"));
+    }
+
+    #[test]
+    fn env_secure() {
+        let env = Env::new()
+            .clear()
+            .set("LD_PRELOAD", "/tmp/x.so")
+            .set("LD_LIBRARY_PATH", "/tmp")
+            .set("GLIBC_TUNABLES", "x")
+            .set("A", "1")
+            .secure();
+        assert_eq!(env.vars(), pairs(&[("A", "1")]));
+        let env = Env::new().secure();
+        assert!(env.vars().iter().all(|(k, _)| k != "LD_PRELOAD"));
+    }
+}
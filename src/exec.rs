@@ -1 +1,493 @@
+use std::{
+    ffi::{CStr, CString, NulError},
+    fmt::Display,
+    os::unix::ffi::OsStrExt,
+    sync::Arc,
+};
 
+use libc::{
+    __errno_location, c_char, c_int, c_uint, c_void, F_ADD_SEALS, F_SEAL_GROW, F_SEAL_SEAL,
+    F_SEAL_SHRINK, F_SEAL_WRITE, MFD_ALLOW_SEALING, MFD_CLOEXEC, S_IFMT, S_IFREG, X_OK,
+};
+
+use crate::{errno_name, Close, Env};
+
+extern "C" {
+    static environ: *const *const c_char;
+}
+
+/*
+ * a program to execve:
+ * 1. path of the program, searched in PATH only when search is enabled
+ * 2. argv[0], the path by default
+ * 3. arguments after argv[0]
+ * 4. environment, resolved by Env when prepared, inherit the current process by default
+ * or an image in a sealed memfd, executed by fexecve
+ *
+ * strings are converted to CString when added, the first NulError is kept and
+ * returned by prepare, so the builder can be chained without unwrap
+ */
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Exec {
+    program: CString,
+    arg0: Option<CString>,
+    args: Vec<CString>,
+    env: Option<Env>,
+    search: bool,
+    search_path: Option<String>,
+    memfd: Option<Arc<Memfd>>,
+    error: Option<NulError>,
+}
+
+// a sealed memfd holding an executable image, closed when the last Exec dropped
+#[derive(Debug, Eq, PartialEq)]
+struct Memfd {
+    fd: c_int,
+}
+
+impl Drop for Memfd {
+    fn drop(&mut self) {
+        Close::close(&[self.fd]).ok();
+    }
+}
+
+// allow exec on kernels with vm.memfd_noexec, unknown to kernels before 6.3
+const MFD_EXEC: c_uint = 0x0010;
+
+// the search path used when neither search_path nor PATH is set, same as glibc
+const DEFAULT_PATH: &str = "/bin:/usr/bin";
+const SHELL: &[u8] = b"/bin/sh\0";
+
+// null terminated argv and envp borrowed from Exec, ready for execve in a forked child
+#[derive(Debug)]
+pub struct PreparedExec<'a> {
+    program: CString,
+    argv: Vec<*const c_char>,
+    // KEY=VALUE entries pointed by envp, None to inherit environ
+    env: Option<Vec<CString>>,
+    envp: Vec<*const c_char>,
+    // argv of /bin/sh when execve fail with ENOEXEC, only for searched program
+    fallback: Option<Vec<*const c_char>>,
+    memfd: Option<c_int>,
+    marker: std::marker::PhantomData<&'a Exec>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExecError {
+    Errno(c_int),
+    CStringParesError(NulError),
+    MemfdErrno(c_int),
+}
+
+impl Display for ExecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecError::Errno(v) => f.write_str(
+                std::format!("execve failed! errno: {} ({})", v, errno_name(*v)).as_str(),
+            ),
+            ExecError::CStringParesError(n) => {
+                f.write_str(std::format!("parse {:<.20} failed!", n.to_string()).as_str())
+            }
+            ExecError::MemfdErrno(v) => f.write_str(
+                std::format!("create memfd failed! errno: {} ({})", v, errno_name(*v)).as_str(),
+            ),
+        }
+    }
+}
+
+impl Exec {
+    pub fn new(program: &str) -> Exec {
+        let mut exec = Exec::default();
+        if let Some(v) = exec.cstring(program) {
+            exec.program = v;
+        }
+        exec
+    }
+
+    /*
+     * copy image into a memfd then seal it, so nothing can change it after checked.
+     * the memfd is close on exec: a #! script can not be run as the interpreter can not
+     * open /proc/self/fd/N any more, only executable files work. argv[0] is name by default
+     */
+    pub fn from_memory(name: &str, image: &[u8]) -> Result<Exec, ExecError> {
+        let cname = CString::new(name).map_err(ExecError::CStringParesError)?;
+        let flags = MFD_CLOEXEC | MFD_ALLOW_SEALING;
+        let fd = match unsafe { libc::memfd_create(cname.as_ptr(), flags | MFD_EXEC) } {
+            -1 if unsafe { *__errno_location() } == libc::EINVAL => unsafe {
+                libc::memfd_create(cname.as_ptr(), flags)
+            },
+            fd => fd,
+        };
+        if fd == -1 {
+            return Err(ExecError::MemfdErrno(unsafe { *__errno_location() }));
+        }
+        let memfd = Memfd { fd };
+        let mut written = 0;
+        while written < image.len() {
+            match unsafe {
+                libc::write(
+                    fd,
+                    image[written..].as_ptr() as *const c_void,
+                    image.len() - written,
+                )
+            } {
+                -1 => match unsafe { *__errno_location() } {
+                    libc::EINTR => continue,
+                    v => return Err(ExecError::MemfdErrno(v)),
+                },
+                v => written += v as usize,
+            }
+        }
+        let seals = F_SEAL_SEAL | F_SEAL_SHRINK | F_SEAL_GROW | F_SEAL_WRITE;
+        if unsafe { libc::fcntl(fd, F_ADD_SEALS, seals) } == -1 {
+            return Err(ExecError::MemfdErrno(unsafe { *__errno_location() }));
+        }
+        let mut exec = Exec::new(std::format!("/proc/self/fd/{}", fd).as_str()).arg0(name);
+        exec.memfd = Some(Arc::new(memfd));
+        Ok(exec)
+    }
+
+    fn cstring(&mut self, s: &str) -> Option<CString> {
+        match CString::new(s) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.error.get_or_insert(e);
+                None
+            }
+        }
+    }
+
+    pub fn arg0(mut self, arg0: &str) -> Exec {
+        self.arg0 = self.cstring(arg0);
+        self
+    }
+
+    pub fn arg(mut self, arg: &str) -> Exec {
+        if let Some(v) = self.cstring(arg) {
+            self.args.push(v);
+        }
+        self
+    }
+
+    pub fn args(self, args: &[&str]) -> Exec {
+        args.iter().fold(self, |exec, arg| exec.arg(arg))
+    }
+
+    // add to the inherited environment, use environment(Env::new().clear()) for an empty one
+    pub fn env(mut self, key: &str, value: &str) -> Exec {
+        let env = self.env.take().unwrap_or_default();
+        self.env = Some(env.set(key, value));
+        self
+    }
+
+    pub fn environment(mut self, env: Env) -> Exec {
+        self.env = Some(env);
+        self
+    }
+
+    /*
+     * look up a program without slash in PATH of current process like execvp:
+     * an empty entry is the current directory, non executable candidates are skipped,
+     * and a file execve refused with ENOEXEC is run by /bin/sh
+     */
+    // ignored by an image from memory
+    pub fn search(mut self) -> Exec {
+        self.search = true;
+        self
+    }
+
+    // search in a colon separated path list instead of PATH
+    pub fn search_path(mut self, path: &str) -> Exec {
+        self.search = true;
+        self.search_path = Some(String::from(path));
+        self
+    }
+
+    pub fn program(&self) -> &CStr {
+        &self.program
+    }
+
+    /*
+     * the file would be executed, without exec it.
+     * ENOENT when nothing found, EACCES when only non executable candidates found
+     */
+    pub fn resolve(&self) -> Result<CString, ExecError> {
+        if let Some(e) = &self.error {
+            return Err(ExecError::CStringParesError(e.clone()));
+        }
+        let program = self.program.as_bytes();
+        if !self.search || self.memfd.is_some() || program.contains(&b'/') {
+            return Ok(self.program.clone());
+        }
+        if program.is_empty() {
+            return Err(ExecError::Errno(libc::ENOENT));
+        }
+        // PATH may be not utf-8, it is searched as bytes
+        let path = match &self.search_path {
+            Some(v) => v.as_bytes().to_vec(),
+            None => std::env::var_os("PATH")
+                .map(|x| x.as_bytes().to_vec())
+                .unwrap_or_else(|| DEFAULT_PATH.as_bytes().to_vec()),
+        };
+        let mut errno = libc::ENOENT;
+        for dir in path.split(|x| *x == b':') {
+            let mut candidate = Vec::with_capacity(dir.len() + program.len() + 1);
+            candidate.extend_from_slice(if dir.is_empty() { b"." } else { dir });
+            candidate.push(b'/');
+            candidate.extend_from_slice(program);
+            let candidate = CString::new(candidate).map_err(ExecError::CStringParesError)?;
+            match executable(&candidate) {
+                Ok(()) => return Ok(candidate),
+                Err(libc::EACCES) => errno = libc::EACCES,
+                Err(_) => (),
+            }
+        }
+        Err(ExecError::Errno(errno))
+    }
+
+    // lower to null terminated arrays, call it before fork as it allocate
+    pub fn prepare(&self) -> Result<PreparedExec<'_>, ExecError> {
+        let program = self.resolve()?;
+        let mut argv = Vec::with_capacity(self.args.len() + 2);
+        argv.push(self.arg0.as_ref().unwrap_or(&self.program).as_ptr());
+        argv.extend(self.args.iter().map(|x| x.as_ptr()));
+        argv.push(std::ptr::null());
+        let env = match &self.env {
+            Some(env) => Some(env.entries().map_err(ExecError::CStringParesError)?),
+            None => None,
+        };
+        let mut envp = Vec::new();
+        if let Some(env) = &env {
+            envp.extend(env.iter().map(|x| x.as_ptr()));
+            envp.push(std::ptr::null());
+        }
+        // /bin/sh file args..., the buffer of program is not moved with PreparedExec
+        let fallback = if self.search && self.memfd.is_none() {
+            let mut fallback = Vec::with_capacity(self.args.len() + 3);
+            fallback.push(SHELL.as_ptr() as *const c_char);
+            fallback.push(program.as_ptr());
+            fallback.extend(self.args.iter().map(|x| x.as_ptr()));
+            fallback.push(std::ptr::null());
+            Some(fallback)
+        } else {
+            None
+        };
+        Ok(PreparedExec {
+            program,
+            argv,
+            env,
+            envp,
+            fallback,
+            memfd: self.memfd.as_ref().map(|x| x.fd),
+            marker: std::marker::PhantomData,
+        })
+    }
+
+    // replace current process, only return when failed
+    pub fn exec(&self) -> ExecError {
+        match self.prepare() {
+            Ok(prepared) => ExecError::Errno(prepared.exec()),
+            Err(e) => e,
+        }
+    }
+}
+
+// a regular file with execute permission, the errno otherwise
+fn executable(path: &CStr) -> Result<(), c_int> {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    if unsafe { libc::stat(path.as_ptr(), stat.as_mut_ptr()) } == -1 {
+        return Err(unsafe { *__errno_location() });
+    }
+    if unsafe { stat.assume_init() }.st_mode & S_IFMT != S_IFREG {
+        return Err(libc::EACCES);
+    }
+    match unsafe { libc::access(path.as_ptr(), X_OK) } {
+        0 => Ok(()),
+        _ => Err(unsafe { *__errno_location() }),
+    }
+}
+
+impl PreparedExec<'_> {
+    pub fn program(&self) -> &CStr {
+        &self.program
+    }
+
+    pub fn argv(&self) -> &[*const c_char] {
+        &self.argv[..]
+    }
+
+    pub fn envp(&self) -> Option<&[*const c_char]> {
+        self.env.as_ref().map(|_| &self.envp[..])
+    }
+
+    // fd of the image from Exec::from_memory, program refer to it by /proc/self/fd
+    pub fn memfd(&self) -> Option<c_int> {
+        self.memfd
+    }
+
+    // async signal safe, only return the errno when execve failed
+    pub fn exec(&self) -> c_int {
+        let envp = match self.envp() {
+            Some(v) => v.as_ptr(),
+            None => unsafe { environ },
+        };
+        // glibc try execveat(fd, "", AT_EMPTY_PATH) then /proc/self/fd/N
+        if let Some(fd) = self.memfd {
+            unsafe { libc::fexecve(fd, self.argv.as_ptr(), envp) };
+            return unsafe { *__errno_location() };
+        }
+        unsafe { libc::execve(self.program.as_ptr(), self.argv.as_ptr(), envp) };
+        let errno = unsafe { *__errno_location() };
+        match &self.fallback {
+            // a script without #!, run it by the shell like execvp
+            Some(fallback) if errno == libc::ENOEXEC => {
+                unsafe { libc::execve(fallback[0], fallback.as_ptr(), envp) };
+                unsafe { *__errno_location() }
+            }
+            _ => errno,
+        }
+    }
+}
+
+#[cfg(test)]
+mod exec {
+    use std::{ffi::CString, fs, os::unix::fs::PermissionsExt, path::PathBuf};
+
+    use crate::{temp_path, Env, Exec, ExecError, Fork};
+
+    // a fresh directory under the temp dir, removed by the caller
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = PathBuf::from(temp_path(name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_file(path: &PathBuf, content: &str, mode: u32) {
+        fs::write(path, content).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    fn run(exec: Exec) -> i32 {
+        let child = Fork::spawn(move || match exec.exec() {
+            ExecError::Errno(v) => v,
+            _ => 255,
+        })
+        .unwrap();
+        let (_, status) = child.wait().unwrap();
+        libc::WEXITSTATUS(status)
+    }
+
+    #[test]
+    fn exec_argv0_and_env() {
+        let exec = Exec::new("/bin/sh")
+            .arg0("libc_tools")
+            .args(&[
+                "-c",
+                "test \"$0\" = libc_tools && test \"$A\" = 1 && exit 3",
+            ])
+            .env("A", "1");
+        assert_eq!(run(exec), 3);
+    }
+
+    #[test]
+    fn exec_env_keep_inherited() {
+        let path = std::env::var("PATH").unwrap();
+        let exec = Exec::new("/bin/sh")
+            .args(&[
+                "-c",
+                "test \"$A\" = 1 && test \"$PATH\" = \"$1\" && exit 3",
+                "sh",
+                path.as_str(),
+            ])
+            .env("A", "1");
+        assert_eq!(run(exec), 3);
+    }
+
+    #[test]
+    fn exec_inherit_env() {
+        let exec = Exec::new("/bin/sh").args(&["-c", "test -n \"$PATH\""]);
+        let prepared = exec.prepare().unwrap();
+        assert_eq!(prepared.argv().len(), 4);
+        assert_eq!(*prepared.argv().last().unwrap(), std::ptr::null());
+        assert!(prepared.envp().is_none());
+        assert_eq!(run(exec), 0);
+    }
+
+    #[test]
+    fn exec_failed() {
+        assert_eq!(run(Exec::new("/libc_tools/not/exist")), libc::ENOENT);
+        let exec = Exec::new("/bin/sh").arg("a\0b").arg("c");
+        assert!(matches!(
+            exec.prepare(),
+            Err(ExecError::CStringParesError(_))
+        ));
+    }
+
+    #[test]
+    fn exec_search_path() {
+        let dir = temp_dir("search_path");
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        fs::create_dir_all(&first).unwrap();
+        fs::create_dir_all(&second).unwrap();
+        // not executable, skipped
+        write_file(&first.join("prog"), "exit 1\n", 0o644);
+        // no #!, run by /bin/sh after ENOEXEC
+        write_file(&second.join("prog"), "exit \"$1\"\n", 0o755);
+        let path = std::format!("{}:{}", first.display(), second.display());
+        let exec = Exec::new("prog").search_path(path.as_str()).arg("5");
+        let expect = CString::new(second.join("prog").to_str().unwrap()).unwrap();
+        assert_eq!(exec.resolve(), Ok(expect));
+        assert_eq!(run(exec), 5);
+        let exec = Exec::new("prog").search_path(first.to_str().unwrap());
+        assert_eq!(exec.resolve(), Err(ExecError::Errno(libc::EACCES)));
+        let exec = Exec::new("not_exist").search_path(path.as_str());
+        assert_eq!(exec.resolve(), Err(ExecError::Errno(libc::ENOENT)));
+        // a directory is never executed
+        let exec = Exec::new("second").search_path(dir.to_str().unwrap());
+        assert_eq!(exec.resolve(), Err(ExecError::Errno(libc::EACCES)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exec_search_env_path() {
+        let exec = Exec::new("sh").search().args(&["-c", "exit 4"]);
+        assert!(exec.resolve().unwrap().as_bytes().ends_with(b"/sh"));
+        assert_eq!(run(exec), 4);
+        // a program with slash is not searched
+        let exec = Exec::new("./sh").search_path("/bin");
+        assert_eq!(exec.resolve(), Ok(CString::new("./sh").unwrap()));
+    }
+
+    #[test]
+    fn exec_from_memory() {
+        let image = fs::read("/bin/sh").unwrap();
+        let exec = Exec::from_memory("libc_tools", &image[..])
+            .unwrap()
+            .args(&["-c", "test \"$0\" = libc_tools && exit 6"]);
+        let fd = exec.memfd.as_ref().unwrap().fd;
+        let seals = unsafe { libc::fcntl(fd, libc::F_GET_SEALS) };
+        assert_eq!(seals & libc::F_SEAL_WRITE, libc::F_SEAL_WRITE);
+        assert_eq!(unsafe { libc::write(fd, "x".as_ptr() as *const _, 1) }, -1);
+        assert_eq!(run(exec.clone()), 6);
+        // the memfd is kept until the last clone dropped
+        assert_eq!(run(exec), 6);
+    }
+
+    #[test]
+    fn exec_environment() {
+        let env = Env::new()
+            .clear()
+            .set("A", "1")
+            .set("LD_PRELOAD", "x")
+            .secure();
+        let exec = Exec::new("/bin/sh")
+            .args(&[
+                "-c",
+                "test \"$A\" = 1 && test -z \"$HOME$LD_PRELOAD\" && exit 7",
+            ])
+            .environment(env);
+        assert_eq!(exec.prepare().unwrap().envp().unwrap().len(), 2);
+        assert_eq!(run(exec), 7);
+    }
+}
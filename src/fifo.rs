@@ -0,0 +1,243 @@
+use std::{
+    ffi::{CString, NulError},
+    fmt::Display,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use libc::{__errno_location, c_int, mode_t, O_CLOEXEC, O_NONBLOCK, O_RDONLY, O_WRONLY};
+
+use crate::{errno_name, PipeReader, PipeWriter};
+
+/*
+ * a named pipe in the file system, both ends are opened with O_NONBLOCK so open never wait
+ * for the other side, then switched back to blocking:
+ * 1. the read end open at once, read return 0 until a writer open it.
+ *    PipeReader::read_timeout poll before read, so it wait for the first writer
+ * 2. the write end fail with NoReader when no reader is there
+ * the FIFO is unlinked on drop if it is created by create or create_at
+ */
+#[derive(Debug)]
+pub struct Fifo {
+    path: CString,
+    // directory of create_at, duplicated so the caller can close its own
+    dir: Option<OwnedFd>,
+    created: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FifoError {
+    Errno(c_int),
+    // open the write end without any reader, ENXIO
+    NoReader,
+    NotFifo,
+    CStringParesError(NulError),
+}
+
+impl Display for FifoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FifoError::Errno(v) => f.write_str(
+                std::format!("fifo operation failed! errno: {} ({})", v, errno_name(*v)).as_str(),
+            ),
+            FifoError::NoReader => f.write_str("open fifo for write without reader!"),
+            FifoError::NotFifo => f.write_str("the file is not a fifo!"),
+            FifoError::CStringParesError(v) => {
+                f.write_str(std::format!("parse {:<.20} failed!", v.to_string()).as_str())
+            }
+        }
+    }
+}
+
+fn errno() -> c_int {
+    unsafe { *__errno_location() }
+}
+
+impl Fifo {
+    // mkfifo, EEXIST when path exists
+    pub fn create(path: &str, mode: mode_t) -> Result<Fifo, FifoError> {
+        let path = CString::new(path).map_err(FifoError::CStringParesError)?;
+        match unsafe { libc::mkfifo(path.as_ptr(), mode) } {
+            -1 => Err(FifoError::Errno(errno())),
+            _ => Ok(Fifo {
+                path,
+                dir: None,
+                created: true,
+            }),
+        }
+    }
+
+    // mkfifoat, a relative path is resolved from the directory dir
+    pub fn create_at(dir: c_int, path: &str, mode: mode_t) -> Result<Fifo, FifoError> {
+        let path = CString::new(path).map_err(FifoError::CStringParesError)?;
+        let dir = match unsafe { libc::fcntl(dir, libc::F_DUPFD_CLOEXEC, 0) } {
+            -1 => return Err(FifoError::Errno(errno())),
+            fd => unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        match unsafe { libc::mkfifoat(dir.as_raw_fd(), path.as_ptr(), mode) } {
+            -1 => Err(FifoError::Errno(errno())),
+            _ => Ok(Fifo {
+                path,
+                dir: Some(dir),
+                created: true,
+            }),
+        }
+    }
+
+    // an existing FIFO, it is kept on drop
+    pub fn open(path: &str) -> Result<Fifo, FifoError> {
+        let path = CString::new(path).map_err(FifoError::CStringParesError)?;
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::stat(path.as_ptr(), &mut stat) } == -1 {
+            return Err(FifoError::Errno(errno()));
+        }
+        if stat.st_mode & libc::S_IFMT != libc::S_IFIFO {
+            return Err(FifoError::NotFifo);
+        }
+        Ok(Fifo {
+            path,
+            dir: None,
+            created: false,
+        })
+    }
+
+    pub fn path(&self) -> &CString {
+        &self.path
+    }
+
+    fn open_end(&self, flags: c_int) -> Result<OwnedFd, FifoError> {
+        let flags = flags | O_NONBLOCK | O_CLOEXEC;
+        let fd = match &self.dir {
+            Some(dir) => unsafe { libc::openat(dir.as_raw_fd(), self.path.as_ptr(), flags) },
+            None => unsafe { libc::open(self.path.as_ptr(), flags) },
+        };
+        let fd = match fd {
+            -1 => match errno() {
+                libc::ENXIO => return Err(FifoError::NoReader),
+                v => return Err(FifoError::Errno(v)),
+            },
+            fd => unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        let raw = fd.as_raw_fd();
+        // the path may be replaced by another file between create and open
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(raw, &mut stat) } == -1 {
+            return Err(FifoError::Errno(errno()));
+        }
+        if stat.st_mode & libc::S_IFMT != libc::S_IFIFO {
+            return Err(FifoError::NotFifo);
+        }
+        match unsafe { libc::fcntl(raw, libc::F_SETFL, flags & !O_NONBLOCK & !O_CLOEXEC) } {
+            -1 => Err(FifoError::Errno(errno())),
+            _ => Ok(fd),
+        }
+    }
+
+    pub fn open_read(&self) -> Result<PipeReader, FifoError> {
+        self.open_end(O_RDONLY).map(PipeReader::from)
+    }
+
+    pub fn open_write(&self) -> Result<PipeWriter, FifoError> {
+        self.open_end(O_WRONLY).map(PipeWriter::from)
+    }
+}
+
+impl Drop for Fifo {
+    fn drop(&mut self) {
+        if !self.created {
+            return;
+        }
+        let result = match &self.dir {
+            Some(dir) => unsafe { libc::unlinkat(dir.as_raw_fd(), self.path.as_ptr(), 0) },
+            None => unsafe { libc::unlink(self.path.as_ptr()) },
+        };
+        if result == -1 {
+            eprintln!("{}", FifoError::Errno(errno()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod fifo {
+    use std::{
+        io::{Read, Write},
+        time::Duration,
+    };
+
+    use crate::{temp_path, Close, Fifo, FifoError};
+
+    #[test]
+    fn fifo_read_write() {
+        let path = temp_path("fifo_read_write");
+        let fifo = Fifo::create(&path, 0o600).unwrap();
+        assert_eq!(fifo.open_write().unwrap_err(), FifoError::NoReader);
+        // neither open block, though the reader is opened before any writer
+        let mut reader = fifo.open_read().unwrap();
+        let mut writer = fifo.open_write().unwrap();
+        writer.write_all(b"hello").unwrap();
+        drop(writer);
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "hello");
+        drop(fifo);
+        assert!(std::fs::metadata(&path).is_err());
+    }
+
+    #[test]
+    fn fifo_wait_writer() {
+        let path = temp_path("fifo_wait_writer");
+        let fifo = Fifo::create(&path, 0o600).unwrap();
+        let mut reader = fifo.open_read().unwrap();
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                let mut writer = Fifo::open(&path).unwrap().open_write().unwrap();
+                writer.write_all(b"hello").unwrap();
+            })
+        };
+        let mut buf = [0u8; 5];
+        reader
+            .read_exact_timeout(&mut buf, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn fifo_open_existing() {
+        let path = temp_path("fifo_open_existing");
+        let fifo = Fifo::create(&path, 0o600).unwrap();
+        assert_eq!(
+            Fifo::create(&path, 0o600).unwrap_err(),
+            FifoError::Errno(libc::EEXIST)
+        );
+        // not created by this one, so kept
+        drop(Fifo::open(&path).unwrap());
+        assert!(std::fs::metadata(&path).is_ok());
+        drop(fifo);
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(Fifo::open(&path).unwrap_err(), FifoError::NotFifo);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fifo_create_at() {
+        let dir = temp_path("fifo_create_at");
+        std::fs::create_dir(&dir).unwrap();
+        let dir_fd = unsafe {
+            libc::open(
+                std::format!("{}\0", dir).as_ptr() as *const _,
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        };
+        assert!(dir_fd >= 0);
+        let fifo = Fifo::create_at(dir_fd, "fifo", 0o600).unwrap();
+        // the directory is duplicated
+        Close::close(&[dir_fd]).unwrap();
+        let _reader = fifo.open_read().unwrap();
+        let _writer = fifo.open_write().unwrap();
+        assert!(std::fs::metadata(std::format!("{}/fifo", dir)).is_ok());
+        drop(fifo);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
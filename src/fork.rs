@@ -1,29 +1,436 @@
-use libc::{getpid, getppid};
+use std::{
+    fmt::Display,
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+use libc::{__errno_location, c_int, getpid, getppid, SYS_clone3};
+
+use crate::{
+    errno_name, namespace::PreparedNamespaces, report::ReportPipe, NamespaceStage, Namespaces,
+    PidfdError, ProcessHandle, Wait,
+};
 
 #[derive(Debug, Clone)]
 pub enum ForkPid {
     Parent((i32, i32)),
     Children((i32, i32)),
-    None,
+}
+
+#[derive(Debug)]
+pub enum PidfdFork {
+    Parent(ProcessHandle),
+    Children((i32, i32)),
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ForkError {
+    Errno(c_int),
+    Namespace(NamespaceStage, c_int),
+    ParentDeathSignal(c_int),
+}
+
+// report stage of ForkError::ParentDeathSignal, NamespaceStage use 0..16
+const STAGE_PARENT_DEATH_SIGNAL: u32 = 16;
+const STAGE_ERRNO: u32 = 17;
+
+impl ForkError {
+    pub fn errno(&self) -> c_int {
+        match *self {
+            ForkError::Errno(v) => v,
+            ForkError::Namespace(_, v) => v,
+            ForkError::ParentDeathSignal(v) => v,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        errno_name(self.errno())
+    }
+}
+
+impl Display for ForkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ForkError::Errno(_) => f.write_str(
+                std::format!("fork failed! errno: {} ({})", self.errno(), self.name()).as_str(),
+            ),
+            ForkError::Namespace(stage, _) => f.write_str(
+                std::format!(
+                    "enter namespaces failed at {}! errno: {} ({})",
+                    stage,
+                    self.errno(),
+                    self.name()
+                )
+                .as_str(),
+            ),
+            ForkError::ParentDeathSignal(_) => f.write_str(
+                std::format!(
+                    "set parent death signal failed! errno: {} ({})",
+                    self.errno(),
+                    self.name()
+                )
+                .as_str(),
+            ),
+        }
+    }
+}
+
+impl ForkError {
+    // (stage, errno) send through the report pipe by the child
+    pub(crate) fn to_report(self) -> (u32, c_int) {
+        match self {
+            ForkError::Errno(v) => (STAGE_ERRNO, v),
+            ForkError::Namespace(stage, v) => (stage as u32, v),
+            ForkError::ParentDeathSignal(v) => (STAGE_PARENT_DEATH_SIGNAL, v),
+        }
+    }
+
+    pub(crate) fn from_report(stage: u32, v: c_int) -> ForkError {
+        match stage {
+            STAGE_PARENT_DEATH_SIGNAL => ForkError::ParentDeathSignal(v),
+            _ => match NamespaceStage::from_u32(stage) {
+                Some(stage) => ForkError::Namespace(stage, v),
+                None => ForkError::Errno(v),
+            },
+        }
+    }
+}
+
+/*
+ * ask the kernel to send signal to the child when the thread which forked it exit,
+ * parent is the pid before fork: if it already died the child is reparented and
+ * the signal is raised here instead, as the kernel would have done
+ */
+pub(crate) fn set_parent_death_signal(signal: c_int, parent: libc::pid_t) -> Result<(), c_int> {
+    if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, signal) } == -1 {
+        return Err(unsafe { *__errno_location() });
+    }
+    if unsafe { getppid() } != parent {
+        unsafe { libc::raise(signal) };
+    }
+    Ok(())
+}
+
+// what the child do before Fork::fork_with return in it
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ForkOptions {
+    pub namespaces: Option<Namespaces>,
+    pub parent_death_signal: Option<c_int>,
+}
+
+// ForkOptions prepared before fork, so the child do not allocate
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PreparedForkOptions {
+    parent: libc::pid_t,
+    parent_death_signal: Option<c_int>,
+    namespaces: Option<PreparedNamespaces>,
+}
+
+impl ForkOptions {
+    pub fn new() -> ForkOptions {
+        ForkOptions::default()
+    }
+
+    pub fn namespaces(mut self, namespaces: Namespaces) -> ForkOptions {
+        self.namespaces = Some(namespaces);
+        self
+    }
+
+    // with a new pid namespace, the relay get the signal and init follow it with SIGKILL
+    pub fn parent_death_signal(mut self, signal: c_int) -> ForkOptions {
+        self.parent_death_signal = Some(signal);
+        self
+    }
+
+    pub(crate) fn prepare(&self) -> PreparedForkOptions {
+        PreparedForkOptions {
+            parent: unsafe { getpid() },
+            parent_death_signal: self.parent_death_signal,
+            namespaces: self.namespaces.as_ref().map(|x| x.prepare()),
+        }
+    }
+}
+
+impl PreparedForkOptions {
+    // nothing to do in the child
+    pub(crate) fn is_empty(&self) -> bool {
+        self.parent_death_signal.is_none() && self.namespaces.is_none()
+    }
+
+    // run in the child, fds are closed in the relay and init of a new pid namespace
+    pub(crate) fn enter(&self, fds: &[c_int]) -> Result<(), ForkError> {
+        if let Some(signal) = self.parent_death_signal {
+            set_parent_death_signal(signal, self.parent).map_err(ForkError::ParentDeathSignal)?;
+        }
+        if let Some(namespaces) = &self.namespaces {
+            namespaces
+                .enter(fds)
+                .map_err(|(stage, v)| ForkError::Namespace(stage, v))?;
+        }
+        Ok(())
+    }
+}
+
+// handle of a child created by Fork::spawn, the child is not reaped until you wait it
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ForkChild {
+    pid: libc::pid_t,
+}
+
+impl ForkChild {
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    pub fn wait(&self) -> Result<(libc::pid_t, libc::c_int), Wait> {
+        loop {
+            match Wait::children_with(self.pid, 0) {
+                Err(Wait::WaitFailure(libc::EINTR)) => continue,
+                result => return result,
+            }
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct Fork;
 
 impl Fork {
-    pub fn fork() -> ForkPid {
+    pub fn fork() -> Result<ForkPid, ForkError> {
         let pid = unsafe { libc::fork() };
         match pid {
-            0 => ForkPid::Children((unsafe { getppid() }, unsafe { getpid() })),
-            1..=std::i32::MAX => ForkPid::Parent((unsafe { getpid() }, pid)),
-            _ => ForkPid::None,
+            0 => Ok(ForkPid::Children((unsafe { getppid() }, unsafe {
+                getpid()
+            }))),
+            1..=std::i32::MAX => Ok(ForkPid::Parent((unsafe { getpid() }, pid))),
+            _ => Err(ForkError::Errno(unsafe { *__errno_location() })),
+        }
+    }
+
+    /*
+     * clone3 with CLONE_PIDFD, fallback to fork and pidfd_open when clone3 is not usable.
+     * clone3 skip the atfork handlers of libc, so the child should only do async signal safe
+     * things (dup2, close, exec...) before exec or _exit
+     */
+    pub fn fork_pidfd() -> Result<PidfdFork, ForkError> {
+        let mut pidfd: c_int = -1;
+        let mut args: libc::clone_args = unsafe { std::mem::zeroed() };
+        args.flags = libc::CLONE_PIDFD as u64;
+        args.pidfd = &mut pidfd as *mut c_int as u64;
+        args.exit_signal = libc::SIGCHLD as u64;
+        let pid = unsafe {
+            libc::syscall(
+                SYS_clone3,
+                &mut args as *mut libc::clone_args,
+                std::mem::size_of::<libc::clone_args>(),
+            )
+        };
+        match pid {
+            0 => Ok(PidfdFork::Children((unsafe { getppid() }, unsafe {
+                getpid()
+            }))),
+            -1 => match unsafe { *__errno_location() } {
+                libc::ENOSYS | libc::EPERM => Self::fork_then_open(),
+                v => Err(ForkError::Errno(v)),
+            },
+            _ => Ok(PidfdFork::Parent(ProcessHandle::from_raw(
+                pid as libc::pid_t,
+                pidfd,
+            ))),
+        }
+    }
+
+    fn fork_then_open() -> Result<PidfdFork, ForkError> {
+        match Self::fork()? {
+            ForkPid::Children(pids) => Ok(PidfdFork::Children(pids)),
+            ForkPid::Parent((_, pid)) => match ProcessHandle::open(pid) {
+                Ok(handle) => Ok(PidfdFork::Parent(handle)),
+                Err(PidfdError::Errno(errno)) => {
+                    unsafe { libc::kill(pid, libc::SIGKILL) };
+                    ForkChild { pid }.wait().ok();
+                    Err(ForkError::Errno(errno))
+                }
+            },
+        }
+    }
+
+    /*
+     * fork and set up the child by options, fork_with only return in the child when the set up
+     * finished, the error in the child is reported to the parent.
+     * with a new pid namespace, the returned child is a relay which exit as the workload does.
+     * the report pipe is only used when there is something to set up: the child never exec so
+     * O_CLOEXEC never close it, a child forked by another thread at the same time inherit the
+     * write end, then recv here wait until that child exit or exec
+     */
+    pub fn fork_with(options: &ForkOptions) -> Result<ForkPid, ForkError> {
+        let options = options.prepare();
+        if options.is_empty() {
+            return Self::fork();
+        }
+        let mut report = ReportPipe::new().map_err(ForkError::Errno)?;
+        match Self::fork()? {
+            ForkPid::Parent((parent, child)) => {
+                report.close_write();
+                match report.recv() {
+                    Ok(None) => Ok(ForkPid::Parent((parent, child))),
+                    Ok(Some((stage, v))) => {
+                        ForkChild { pid: child }.wait().ok();
+                        Err(ForkError::from_report(stage, v))
+                    }
+                    // the child is not returned, so it can not be left running or unreaped
+                    Err(v) => {
+                        unsafe { libc::kill(child, libc::SIGKILL) };
+                        ForkChild { pid: child }.wait().ok();
+                        Err(ForkError::Errno(v))
+                    }
+                }
+            }
+            ForkPid::Children(_) => {
+                report.close_read();
+                if let Err(e) = options.enter(&[report.write_fd()]) {
+                    let (stage, v) = e.to_report();
+                    report.send(stage, v);
+                    unsafe { libc::_exit(127) };
+                }
+                report.close_write();
+                Ok(ForkPid::Children((unsafe { getppid() }, unsafe {
+                    getpid()
+                })))
+            }
+        }
+    }
+
+    // run f in the child and _exit with its return value, a panic in f exits with 101
+    pub fn spawn<F>(f: F) -> Result<ForkChild, ForkError>
+    where
+        F: FnOnce() -> i32,
+    {
+        Self::spawn_with(&ForkOptions::default(), f)
+    }
+
+    pub fn spawn_with<F>(options: &ForkOptions, f: F) -> Result<ForkChild, ForkError>
+    where
+        F: FnOnce() -> i32,
+    {
+        match Self::fork_with(options)? {
+            ForkPid::Parent((_, pid)) => Ok(ForkChild { pid }),
+            ForkPid::Children(_) => {
+                let code = catch_unwind(AssertUnwindSafe(f)).unwrap_or(101);
+                unsafe { libc::_exit(code) }
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod fork {
+    use libc::c_void;
+
+    use crate::{Fork, ForkError, ForkOptions, ForkPid};
+
+    static mut COUNTER: i32 = 0;
 
     #[test]
     fn fork_box() {}
+
+    #[test]
+    fn spawn_exit_code() {
+        let child = Fork::spawn(|| 7).unwrap();
+        let (pid, status) = child.wait().unwrap();
+        assert_eq!(pid, child.pid());
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 7);
+    }
+
+    #[test]
+    fn spawn_panic() {
+        let child = Fork::spawn(|| panic!("panic in child")).unwrap();
+        let (_, status) = child.wait().unwrap();
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 101);
+    }
+
+    #[test]
+    fn spawn_isolate_global_state() {
+        let child = Fork::spawn(|| unsafe {
+            COUNTER += 1;
+            COUNTER
+        })
+        .unwrap();
+        let (_, status) = child.wait().unwrap();
+        assert_eq!(libc::WEXITSTATUS(status), 1);
+        assert_eq!(unsafe { COUNTER }, 0);
+    }
+
+    #[test]
+    fn fork_error_name() {
+        let error = ForkError::Errno(libc::EAGAIN);
+        assert_eq!(error.errno(), libc::EAGAIN);
+        assert_eq!(error.name(), "EAGAIN");
+        assert_eq!(
+            std::format!("{}", error),
+            std::format!("fork failed! errno: {} (EAGAIN)", libc::EAGAIN)
+        );
+    }
+
+    #[test]
+    fn parent_death_signal() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        let write_fd = fds[1];
+        // the middle child exit after fork, so the grandchild should be killed
+        let middle = Fork::spawn(move || {
+            let options = ForkOptions::new().parent_death_signal(libc::SIGKILL);
+            match Fork::fork_with(&options) {
+                Ok(ForkPid::Parent((_, pid))) => {
+                    unsafe { libc::write(write_fd, &pid as *const i32 as *const c_void, 4) };
+                    0
+                }
+                Ok(ForkPid::Children(_)) => loop {
+                    unsafe { libc::pause() };
+                },
+                Err(_) => 1,
+            }
+        })
+        .unwrap();
+        unsafe { libc::close(fds[1]) };
+        let mut pid = 0;
+        let size = unsafe { libc::read(fds[0], &mut pid as *mut i32 as *mut c_void, 4) };
+        unsafe { libc::close(fds[0]) };
+        assert_eq!(size, 4);
+        let (_, status) = middle.wait().unwrap();
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+        // the orphan is reaped by the init process, or stay as a zombie
+        let mut retry = 0;
+        while let Ok(stat) = std::fs::read_to_string(std::format!("/proc/{}/stat", pid)) {
+            if stat
+                .rsplit(')')
+                .next()
+                .unwrap()
+                .trim_start()
+                .starts_with('Z')
+            {
+                break;
+            }
+            assert!(retry < 500, "orphan is still alive");
+            retry += 1;
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn fork_error_report() {
+        for error in &[
+            ForkError::Errno(libc::EAGAIN),
+            ForkError::Namespace(crate::NamespaceStage::GidMap, libc::EPERM),
+            ForkError::ParentDeathSignal(libc::EINVAL),
+        ] {
+            let (stage, v) = error.to_report();
+            assert_eq!(ForkError::from_report(stage, v), *error);
+        }
+        let error = ForkOptions::new()
+            .parent_death_signal(-1)
+            .prepare()
+            .enter(&[]);
+        assert_eq!(error, Err(ForkError::ParentDeathSignal(libc::EINVAL)));
+    }
 }
@@ -1,25 +1,55 @@
 mod close;
+mod daemon;
 mod dup;
+mod env;
+mod errno;
 mod exec;
+mod fifo;
 mod fork;
+mod namespace;
+mod pidfd;
 mod pipe;
+mod pipeline;
 mod popen;
 mod proc;
 mod pty;
+mod quote;
+mod report;
 mod run;
 mod socket_pair;
+mod spawn;
+mod splice;
 mod wait;
 pub use close::*;
 
 pub use close::*;
+pub use daemon::*;
 pub use dup::*;
+pub use env::*;
+pub use errno::*;
 pub use exec::*;
+pub use fifo::*;
 pub use fork::*;
+pub use namespace::*;
+pub use pidfd::*;
 pub use pipe::*;
+pub use pipeline::*;
 pub use popen::*;
 pub use proc::*;
 pub use pty::*;
+pub use quote::*;
 pub use socket_pair::*;
+pub use spawn::*;
+pub use splice::*;
 pub use wait::*;
+
+// a path under the temp dir unique to the test process, nothing is created
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(std::format!("libc_tools_{}_{}", name, std::process::id()))
+        .to_string_lossy()
+        .to_string()
+}
 #[cfg(test)]
 mod lib {}
@@ -0,0 +1,322 @@
+use std::fmt::Display;
+
+use libc::{
+    __errno_location, c_int, c_void, gid_t, pid_t, uid_t, CLONE_NEWIPC, CLONE_NEWNET, CLONE_NEWNS,
+    CLONE_NEWPID, CLONE_NEWUSER, CLONE_NEWUTS, O_CLOEXEC, O_WRONLY,
+};
+
+use crate::Close;
+
+/*
+ * namespaces entered by the child right after fork:
+ * 1. unshare all namespaces at once, the user namespace is created first by the kernel
+ * 2. deny setgroups then write uid_map and gid_map, by default the current user is root inside
+ * 3. a new pid namespace only apply to the children of the caller, so fork twice:
+ *    child(relay) -> init(pid 1, reap every orphan) -> workload(pid 2)
+ *    the relay exit with the status of the workload, 128 + signal when it is killed
+ */
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Namespaces {
+    flags: c_int,
+    uid_map: Option<(uid_t, uid_t, u32)>,
+    gid_map: Option<(gid_t, gid_t, u32)>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum NamespaceStage {
+    Unshare,
+    Setgroups,
+    UidMap,
+    GidMap,
+    Fork,
+}
+
+impl NamespaceStage {
+    pub(crate) fn from_u32(v: u32) -> Option<NamespaceStage> {
+        [
+            NamespaceStage::Unshare,
+            NamespaceStage::Setgroups,
+            NamespaceStage::UidMap,
+            NamespaceStage::GidMap,
+            NamespaceStage::Fork,
+        ]
+        .get(v as usize)
+        .copied()
+    }
+}
+
+impl Display for NamespaceStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match *self {
+            NamespaceStage::Unshare => "unshare",
+            NamespaceStage::Setgroups => "write setgroups",
+            NamespaceStage::UidMap => "write uid_map",
+            NamespaceStage::GidMap => "write gid_map",
+            NamespaceStage::Fork => "fork init",
+        })
+    }
+}
+
+// "inside outside count\n" of uid_map and gid_map, formatted before fork
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct IdMap {
+    buf: [u8; 40],
+    len: usize,
+}
+
+impl IdMap {
+    fn new(inside: u32, outside: u32, count: u32) -> IdMap {
+        let mut map = IdMap {
+            buf: [0; 40],
+            len: 0,
+        };
+        for (i, v) in [inside, outside, count].iter().enumerate() {
+            let start = map.len;
+            let mut v = *v;
+            while {
+                map.buf[map.len] = b'0' + (v % 10) as u8;
+                map.len += 1;
+                v /= 10;
+                v != 0
+            } {}
+            map.buf[start..map.len].reverse();
+            map.buf[map.len] = if i == 2 { b'\n' } else { b' ' };
+            map.len += 1;
+        }
+        map
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+// everything the child need, prepared in the parent so enter do not allocate
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PreparedNamespaces {
+    flags: c_int,
+    uid_map: IdMap,
+    gid_map: IdMap,
+}
+
+fn errno() -> c_int {
+    unsafe { *__errno_location() }
+}
+
+fn write_file(path: &[u8], content: &[u8]) -> Result<(), c_int> {
+    let fd = unsafe { libc::open(path.as_ptr() as *const libc::c_char, O_WRONLY | O_CLOEXEC) };
+    if fd == -1 {
+        return Err(errno());
+    }
+    let result = unsafe { libc::write(fd, content.as_ptr() as *const c_void, content.len()) };
+    let errno = errno();
+    Close::close(&[fd]).ok();
+    match result {
+        -1 => Err(errno),
+        _ => Ok(()),
+    }
+}
+
+// convert a wait status to an exit code, so the relay can pass it on with _exit
+fn exit_code(status: c_int) -> c_int {
+    if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        libc::WEXITSTATUS(status)
+    }
+}
+
+impl Namespaces {
+    pub fn new() -> Namespaces {
+        Namespaces::default()
+    }
+
+    pub fn user(mut self) -> Namespaces {
+        self.flags |= CLONE_NEWUSER;
+        self
+    }
+
+    pub fn pid(mut self) -> Namespaces {
+        self.flags |= CLONE_NEWPID;
+        self
+    }
+
+    pub fn mount(mut self) -> Namespaces {
+        self.flags |= CLONE_NEWNS;
+        self
+    }
+
+    pub fn net(mut self) -> Namespaces {
+        self.flags |= CLONE_NEWNET;
+        self
+    }
+
+    pub fn uts(mut self) -> Namespaces {
+        self.flags |= CLONE_NEWUTS;
+        self
+    }
+
+    pub fn ipc(mut self) -> Namespaces {
+        self.flags |= CLONE_NEWIPC;
+        self
+    }
+
+    // map [outside, outside + count) of the parent namespace to [inside, inside + count)
+    pub fn uid_map(mut self, inside: uid_t, outside: uid_t, count: u32) -> Namespaces {
+        self.uid_map = Some((inside, outside, count));
+        self
+    }
+
+    pub fn gid_map(mut self, inside: gid_t, outside: gid_t, count: u32) -> Namespaces {
+        self.gid_map = Some((inside, outside, count));
+        self
+    }
+
+    pub fn flags(&self) -> c_int {
+        self.flags
+    }
+
+    pub(crate) fn prepare(&self) -> PreparedNamespaces {
+        let (uid_inside, uid_outside, uid_count) =
+            self.uid_map.unwrap_or((0, unsafe { libc::geteuid() }, 1));
+        let (gid_inside, gid_outside, gid_count) =
+            self.gid_map.unwrap_or((0, unsafe { libc::getegid() }, 1));
+        PreparedNamespaces {
+            flags: self.flags,
+            uid_map: IdMap::new(uid_inside, uid_outside, uid_count),
+            gid_map: IdMap::new(gid_inside, gid_outside, gid_count),
+        }
+    }
+}
+
+impl PreparedNamespaces {
+    /*
+     * run in the child right after fork, return in the workload process only.
+     * fds are closed in the relay and init process, so they never hold the pipes of the workload
+     */
+    pub(crate) fn enter(&self, fds: &[c_int]) -> Result<(), (NamespaceStage, c_int)> {
+        if self.flags == 0 {
+            return Ok(());
+        }
+        if unsafe { libc::unshare(self.flags) } == -1 {
+            return Err((NamespaceStage::Unshare, errno()));
+        }
+        if self.flags & CLONE_NEWUSER != 0 {
+            write_file(b"/proc/self/setgroups\0", b"deny")
+                .map_err(|v| (NamespaceStage::Setgroups, v))?;
+            write_file(b"/proc/self/uid_map\0", self.uid_map.as_bytes())
+                .map_err(|v| (NamespaceStage::UidMap, v))?;
+            write_file(b"/proc/self/gid_map\0", self.gid_map.as_bytes())
+                .map_err(|v| (NamespaceStage::GidMap, v))?;
+        }
+        if self.flags & CLONE_NEWPID == 0 {
+            return Ok(());
+        }
+        match unsafe { libc::fork() } {
+            -1 => Err((NamespaceStage::Fork, errno())),
+            0 => Self::init(fds),
+            init => {
+                Close::close(fds).ok();
+                unsafe { libc::_exit(exit_code(Self::reap(init))) }
+            }
+        }
+    }
+
+    // pid 1 of the new namespace, fork the workload then reap until it exit
+    fn init(fds: &[c_int]) -> Result<(), (NamespaceStage, c_int)> {
+        // the whole namespace is killed when init exit, so follow the relay
+        unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+        match unsafe { libc::fork() } {
+            -1 => Err((NamespaceStage::Fork, errno())),
+            0 => Ok(()),
+            workload => {
+                Close::close(fds).ok();
+                unsafe { libc::_exit(exit_code(Self::reap(workload))) }
+            }
+        }
+    }
+
+    // wait any child until pid exit, so orphans reparented to init are reaped too
+    fn reap(pid: pid_t) -> c_int {
+        let mut status = 0;
+        loop {
+            match unsafe { libc::waitpid(-1, &mut status, 0) } {
+                -1 if errno() == libc::EINTR => continue,
+                -1 => return status,
+                v if v == pid => return status,
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod namespace {
+    use crate::{Fork, ForkError, ForkOptions, ForkPid, NamespaceStage, Namespaces};
+
+    // user namespace may be disabled, eg: sysctl or apparmor, skip the test then
+    fn fork_in(namespaces: Namespaces) -> Option<ForkPid> {
+        match Fork::fork_with(&ForkOptions::new().namespaces(namespaces)) {
+            Ok(v) => Some(v),
+            Err(ForkError::Namespace(_, libc::EPERM))
+            | Err(ForkError::Namespace(_, libc::ENOSPC)) => None,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn user_namespace_root() {
+        match fork_in(Namespaces::new().user()) {
+            Some(ForkPid::Parent((_, child))) => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+            }
+            Some(ForkPid::Children(_)) => unsafe {
+                libc::_exit(match (libc::geteuid(), libc::getegid()) {
+                    (0, 0) => 0,
+                    _ => 1,
+                })
+            },
+            None => (),
+        }
+    }
+
+    #[test]
+    fn pid_namespace_init() {
+        match fork_in(Namespaces::new().user().pid().uts()) {
+            Some(ForkPid::Parent((_, child))) => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+                assert_eq!(libc::WEXITSTATUS(status), 42);
+            }
+            Some(ForkPid::Children((parent, pid))) => unsafe {
+                let name = "libc_tools";
+                let code = match libc::sethostname(name.as_ptr() as *const libc::c_char, name.len())
+                {
+                    0 if parent == 1 && pid == 2 => 42,
+                    _ => 1,
+                };
+                libc::_exit(code)
+            },
+            None => (),
+        }
+    }
+
+    #[test]
+    fn namespace_failed() {
+        let result = Fork::fork_with(
+            &ForkOptions::new().namespaces(Namespaces::new().user().uid_map(0, 0, 0)),
+        );
+        match result {
+            Err(ForkError::Namespace(NamespaceStage::UidMap, libc::EINVAL)) => (),
+            Err(ForkError::Namespace(_, libc::EPERM)) => (),
+            Ok(ForkPid::Children(_)) => unsafe { libc::_exit(0) },
+            Ok(ForkPid::Parent((_, child))) => {
+                unsafe { libc::waitpid(child, std::ptr::null_mut(), 0) };
+                panic!("empty uid map should fail")
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
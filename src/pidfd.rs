@@ -0,0 +1,157 @@
+use std::{fmt::Display, os::unix::io::AsRawFd};
+
+use libc::{__errno_location, c_int, pid_t, pollfd, SYS_pidfd_open, SYS_pidfd_send_signal, POLLIN};
+
+use crate::{errno_name, Close, Wait};
+
+/*
+ * a pidfd refers to the process itself instead of its pid number,
+ * so signal and wait through it will never hit a process which reuse the pid
+ * after the child exit and reaped by someone else
+ */
+#[derive(Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ProcessHandle {
+    pid: pid_t,
+    pidfd: c_int,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PidfdError {
+    Errno(c_int),
+}
+
+impl Display for PidfdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            PidfdError::Errno(v) => f.write_str(
+                std::format!("pidfd operation failed! errno: {} ({})", v, errno_name(v)).as_str(),
+            ),
+        }
+    }
+}
+
+impl ProcessHandle {
+    // the caller should own the pidfd, it will be closed when the handle dropped
+    pub(crate) fn from_raw(pid: pid_t, pidfd: c_int) -> ProcessHandle {
+        ProcessHandle { pid, pidfd }
+    }
+
+    // only race free when pid is an unreaped child of the caller
+    pub fn open(pid: pid_t) -> Result<ProcessHandle, PidfdError> {
+        match unsafe { libc::syscall(SYS_pidfd_open, pid, 0) } {
+            -1 => Err(PidfdError::Errno(unsafe { *__errno_location() })),
+            fd => Ok(ProcessHandle::from_raw(pid, fd as c_int)),
+        }
+    }
+
+    pub fn pid(&self) -> pid_t {
+        self.pid
+    }
+
+    pub fn send_signal(&self, signal: c_int) -> Result<(), PidfdError> {
+        match unsafe {
+            libc::syscall(
+                SYS_pidfd_send_signal,
+                self.pidfd,
+                signal,
+                std::ptr::null::<libc::siginfo_t>(),
+                0,
+            )
+        } {
+            -1 => Err(PidfdError::Errno(unsafe { *__errno_location() })),
+            _ => Ok(()),
+        }
+    }
+
+    // pidfd become readable when the process exit, timeout -1 to block until exit
+    pub fn poll_exit(&self, timeout: c_int) -> Result<bool, PidfdError> {
+        let mut fds = [pollfd {
+            fd: self.pidfd,
+            events: POLLIN,
+            revents: 0,
+        }];
+        loop {
+            match unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout) } {
+                -1 => match unsafe { *__errno_location() } {
+                    libc::EINTR => continue,
+                    v => return Err(PidfdError::Errno(v)),
+                },
+                0 => return Ok(false),
+                _ => return Ok(fds[0].revents & POLLIN != 0),
+            }
+        }
+    }
+
+    pub fn wait(&self, options: c_int) -> Result<(pid_t, c_int), Wait> {
+        loop {
+            match Wait::pidfd(self.pidfd, options) {
+                Err(Wait::WaitFailure(libc::EINTR)) => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl AsRawFd for ProcessHandle {
+    fn as_raw_fd(&self) -> c_int {
+        self.pidfd
+    }
+}
+
+impl Drop for ProcessHandle {
+    fn drop(&mut self) {
+        if let Err(v) = Close::close(&[self.pidfd]) {
+            eprintln!("{}", v)
+        }
+    }
+}
+
+#[cfg(test)]
+mod pidfd {
+    use crate::{Fork, PidfdFork};
+
+    #[test]
+    fn wait_exit_code() {
+        match Fork::fork_pidfd().unwrap() {
+            PidfdFork::Parent(handle) => {
+                let (pid, status) = handle.wait(0).unwrap();
+                assert_eq!(pid, handle.pid());
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(libc::WEXITSTATUS(status), 3);
+            }
+            PidfdFork::Children(_) => unsafe { libc::_exit(3) },
+        }
+    }
+
+    #[test]
+    fn signal_and_poll() {
+        match Fork::fork_pidfd().unwrap() {
+            PidfdFork::Parent(handle) => {
+                assert!(!handle.poll_exit(0).unwrap());
+                handle.send_signal(libc::SIGKILL).unwrap();
+                assert!(handle.poll_exit(-1).unwrap());
+                let (_, status) = handle.wait(0).unwrap();
+                assert!(libc::WIFSIGNALED(status));
+                assert_eq!(libc::WTERMSIG(status), libc::SIGKILL);
+            }
+            PidfdFork::Children(_) => loop {
+                unsafe { libc::pause() };
+            },
+        }
+    }
+
+    #[test]
+    fn wait_no_hang() {
+        match Fork::fork_pidfd().unwrap() {
+            PidfdFork::Parent(handle) => {
+                assert!(handle.wait(libc::WNOHANG).is_err());
+                handle.send_signal(libc::SIGTERM).unwrap();
+                let (_, status) = handle.wait(0).unwrap();
+                assert_eq!(libc::WTERMSIG(status), libc::SIGTERM);
+            }
+            PidfdFork::Children(_) => loop {
+                unsafe { libc::pause() };
+            },
+        }
+    }
+}
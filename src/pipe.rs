@@ -1,6 +1,14 @@
-use crate::Close;
-use libc::c_int;
-use std::fmt::Display;
+use crate::{errno_name, Close};
+use libc::{
+    __errno_location, c_int, c_short, c_void, pollfd, FIONREAD, F_GETPIPE_SZ, F_SETPIPE_SZ,
+    O_DIRECT, PIPE_BUF, POLLIN, POLLOUT,
+};
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+    os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+    time::{Duration, Instant},
+};
 
 pub enum PipeSide {
     Read,
@@ -8,38 +16,447 @@ pub enum PipeSide {
 }
 
 // the other side of the pipe need to closed when you need to use one side, or one process will be suspend
-#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Pipe {
     fd: (c_int, c_int),
 }
 
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PipeError {
+    Errno(c_int),
+    Timeout,
+    // read_exact_timeout timed out, bytes read before it
+    TimeoutAfter(usize),
+    // the writer closed before the buffer is filled, bytes read before eof
+    UnexpectedEof(usize),
+    // size of the message larger than PIPE_BUF, a packet can not hold it
+    MessageTooLarge(usize),
+    // send on a pipe without O_DIRECT, the message boundary would be lost
+    NotPacket,
+}
+
+impl Display for PipeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            PipeError::Errno(v) => f.write_str(
+                std::format!("pipe operation failed! errno: {} ({})", v, errno_name(v)).as_str(),
+            ),
+            PipeError::Timeout => f.write_str("pipe operation timed out!"),
+            PipeError::TimeoutAfter(v) => {
+                f.write_str(std::format!("pipe operation timed out after {} bytes!", v).as_str())
+            }
+            PipeError::UnexpectedEof(v) => {
+                f.write_str(std::format!("pipe closed after {} bytes!", v).as_str())
+            }
+            PipeError::MessageTooLarge(v) => f.write_str(
+                std::format!("message of {} bytes is larger than {}!", v, PIPE_BUF).as_str(),
+            ),
+            PipeError::NotPacket => f.write_str("the pipe is not in packet mode!"),
+        }
+    }
+}
+
+// the read end of a pipe, only close its own fd when dropped
+#[derive(Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PipeReader {
+    fd: c_int,
+}
+
+// the write end of a pipe, only close its own fd when dropped
+#[derive(Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PipeWriter {
+    fd: c_int,
+}
+
 impl Pipe {
     pub fn new(&fds: &[libc::c_int; 2]) -> Pipe {
         Pipe {
             fd: (fds[0], fds[1]),
         }
     }
-    pub fn pipe() -> Option<Pipe> {
+    // the errno is taken at once, so later libc calls can not change it
+    pub fn pipe() -> Result<Pipe, c_int> {
         let mut end = [0; 2];
         match unsafe { libc::pipe(end.as_mut_ptr()) } {
-            libc::INT_MIN..=-1 => None,
-            _ => Some(Pipe::new(&end)),
+            -1 => Err(unsafe { *__errno_location() }),
+            _ => Ok(Pipe::new(&end)),
         }
     }
 
-    pub fn pipe2(flag: i32) -> Option<Pipe> {
+    pub fn pipe2(flag: i32) -> Result<Pipe, c_int> {
         let mut end = [0; 2];
         match unsafe { libc::pipe2(end.as_mut_ptr(), flag) } {
-            libc::INT_MIN..=-1 => None,
-            _ => Some(Pipe::new(&end)),
+            -1 => Err(unsafe { *__errno_location() }),
+            _ => Ok(Pipe::new(&end)),
         }
     }
 
+    /*
+     * packet mode pipe, pipe2 with O_DIRECT | flag: every write is a packet and a read
+     * return one packet only, use PipeWriter::send and PipeReader::recv for messages
+     */
+    pub fn packet(flag: i32) -> Result<Pipe, c_int> {
+        Self::pipe2(flag | O_DIRECT)
+    }
+
+    // O_DIRECT is only set on the write end, which decide how data is written
+    pub fn is_packet(&self) -> Result<bool, PipeError> {
+        is_packet(self.fd.1)
+    }
+
     pub fn get(&self, side: PipeSide) -> c_int {
         match side {
-            PipeSide::Read => self.fd.1,
-            PipeSide::Write => self.fd.0,
+            PipeSide::Read => self.fd.0,
+            PipeSide::Write => self.fd.1,
+        }
+    }
+
+    // limit of set_capacity for unprivileged users, read from /proc/sys/fs/pipe-max-size
+    pub fn max_capacity() -> Result<usize, PipeError> {
+        let max = std::fs::read_to_string("/proc/sys/fs/pipe-max-size")
+            .map_err(|e| PipeError::Errno(e.raw_os_error().unwrap_or(libc::EIO)))?;
+        max.trim()
+            .parse()
+            .map_err(|_| PipeError::Errno(libc::EINVAL))
+    }
+
+    // buffer size of the pipe in bytes, 64 KiB by default
+    pub fn capacity(&self) -> Result<usize, PipeError> {
+        capacity(self.fd.0)
+    }
+
+    /*
+     * the kernel round size up to a power of two pages, the rounded size is returned.
+     * size larger than max_capacity is clamped, EBUSY when size is less than buffered bytes
+     */
+    pub fn set_capacity(&self, size: usize) -> Result<usize, PipeError> {
+        set_capacity(self.fd.0, size)
+    }
+
+    // bytes buffered in the pipe and not read yet
+    pub fn available(&self) -> Result<usize, PipeError> {
+        available(self.fd.0)
+    }
+
+    /*
+     * N pipes at once, the errno of the failed pipe is returned and the pipes already
+     * created are closed. N should not be 0
+     */
+    pub fn pipes<const N: usize>() -> Result<[Pipe; N], PipeError> {
+        Self::pipes2([0; N])
+    }
+
+    // same as pipes, the i-th pipe is created by pipe2 with flags[i]
+    pub fn pipes2<const N: usize>(flags: [c_int; N]) -> Result<[Pipe; N], PipeError> {
+        if N == 0 {
+            return Err(PipeError::Errno(libc::EINVAL));
+        }
+        let mut pipes = Vec::with_capacity(N);
+        for flag in flags.iter() {
+            match Pipe::pipe2(*flag) {
+                Ok(pipe) => pipes.push(pipe),
+                // pipes dropped here close every fd created
+                Err(v) => return Err(PipeError::Errno(v)),
+            }
+        }
+        match std::convert::TryInto::<[Pipe; N]>::try_into(pipes) {
+            Ok(pipes) => Ok(pipes),
+            Err(_) => unreachable!("{} pipes are created", N),
+        }
+    }
+
+    // give up the ownership, the caller should close [read, write]
+    pub fn into_raw_fds(self) -> [c_int; 2] {
+        let (read, write) = self.fd;
+        std::mem::forget(self);
+        [read, write]
+    }
+
+    // hand the two ends to different owners, eg: keep the reader and pass the writer to a child
+    pub fn split(self) -> (PipeReader, PipeWriter) {
+        let (read, write) = self.fd;
+        std::mem::forget(self);
+        (PipeReader { fd: read }, PipeWriter { fd: write })
+    }
+}
+
+fn capacity(fd: c_int) -> Result<usize, PipeError> {
+    match unsafe { libc::fcntl(fd, F_GETPIPE_SZ) } {
+        -1 => Err(PipeError::Errno(unsafe { *__errno_location() })),
+        v => Ok(v as usize),
+    }
+}
+
+fn set_capacity(fd: c_int, size: usize) -> Result<usize, PipeError> {
+    let size = match Pipe::max_capacity() {
+        Ok(max) => size.min(max),
+        Err(_) => size,
+    };
+    let size = size.min(c_int::MAX as usize) as c_int;
+    match unsafe { libc::fcntl(fd, F_SETPIPE_SZ, size) } {
+        -1 => Err(PipeError::Errno(unsafe { *__errno_location() })),
+        v => Ok(v as usize),
+    }
+}
+
+fn available(fd: c_int) -> Result<usize, PipeError> {
+    let mut size: c_int = 0;
+    match unsafe { libc::ioctl(fd, FIONREAD, &mut size as *mut c_int) } {
+        -1 => Err(PipeError::Errno(unsafe { *__errno_location() })),
+        _ => Ok(size as usize),
+    }
+}
+
+fn is_packet(fd: c_int) -> Result<bool, PipeError> {
+    match unsafe { libc::fcntl(fd, libc::F_GETFL) } {
+        -1 => Err(PipeError::Errno(unsafe { *__errno_location() })),
+        v => Ok(v & O_DIRECT != 0),
+    }
+}
+
+// now + timeout, None when it overflow, eg: Duration::MAX, which means no timeout
+fn deadline(timeout: Duration) -> Option<Instant> {
+    Instant::now().checked_add(timeout)
+}
+
+/*
+ * poll until fd is ready for events or the deadline passed, hang up and errors count as ready.
+ * without deadline poll wait forever
+ */
+fn wait_ready(fd: c_int, events: c_short, deadline: Option<Instant>) -> Result<(), PipeError> {
+    let mut poll = pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    loop {
+        let ms = match deadline {
+            None => -1,
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                // round up, or poll return early and spin for the last millisecond
+                let ms = left.as_nanos().div_ceil(1_000_000);
+                ms.min(c_int::MAX as u128) as c_int
+            }
+        };
+        match unsafe { libc::poll(&mut poll, 1, ms) } {
+            -1 => match unsafe { *__errno_location() } {
+                libc::EINTR => continue,
+                v => return Err(PipeError::Errno(v)),
+            },
+            0 if ms == 0 => return Err(PipeError::Timeout),
+            0 => continue,
+            _ => return Ok(()),
+        }
+    }
+}
+
+/*
+ * read or write once when fd is ready before the deadline, retry on EINTR and EAGAIN.
+ * fd can be blocking, but a blocking write larger than PIPE_BUF may still block after poll
+ */
+fn io_until<F: FnMut() -> isize>(
+    fd: c_int,
+    events: c_short,
+    deadline: Option<Instant>,
+    mut f: F,
+) -> Result<usize, PipeError> {
+    loop {
+        wait_ready(fd, events, deadline)?;
+        match f() {
+            -1 => match unsafe { *__errno_location() } {
+                libc::EINTR | libc::EAGAIN => continue,
+                v => return Err(PipeError::Errno(v)),
+            },
+            v => return Ok(v as usize),
+        }
+    }
+}
+
+macro_rules! pipe_end {
+    ($end: ident) => {
+        impl $end {
+            // same as Pipe::capacity, the capacity is shared by both ends
+            pub fn capacity(&self) -> Result<usize, PipeError> {
+                capacity(self.fd)
+            }
+
+            pub fn set_capacity(&self, size: usize) -> Result<usize, PipeError> {
+                set_capacity(self.fd, size)
+            }
+
+            pub fn available(&self) -> Result<usize, PipeError> {
+                available(self.fd)
+            }
+        }
+
+        impl Drop for $end {
+            fn drop(&mut self) {
+                match Close::close(&[self.fd]) {
+                    Ok(_) => (),
+                    Err(v) => eprintln!("{}", v),
+                }
+            }
+        }
+
+        impl AsRawFd for $end {
+            fn as_raw_fd(&self) -> RawFd {
+                self.fd
+            }
+        }
+
+        impl AsFd for $end {
+            fn as_fd(&self) -> BorrowedFd<'_> {
+                unsafe { BorrowedFd::borrow_raw(self.fd) }
+            }
+        }
+
+        impl IntoRawFd for $end {
+            fn into_raw_fd(self) -> RawFd {
+                let fd = self.fd;
+                std::mem::forget(self);
+                fd
+            }
+        }
+
+        // the caller should make sure the fd is the right end of a pipe
+        impl From<OwnedFd> for $end {
+            fn from(fd: OwnedFd) -> $end {
+                $end {
+                    fd: fd.into_raw_fd(),
+                }
+            }
+        }
+
+        impl From<$end> for OwnedFd {
+            fn from(end: $end) -> OwnedFd {
+                unsafe { OwnedFd::from_raw_fd(end.into_raw_fd()) }
+            }
+        }
+    };
+}
+
+pipe_end!(PipeReader);
+pipe_end!(PipeWriter);
+
+impl PipeReader {
+    /*
+     * read one packet of a packet mode pipe, 0 means eof.
+     * the part of a packet larger than buf is discarded, a buf of PIPE_BUF hold any message.
+     * the read end do not know the mode, on an ordinary pipe this is a plain read
+     */
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize, PipeError> {
+        loop {
+            match unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len()) } {
+                -1 => match unsafe { *__errno_location() } {
+                    libc::EINTR => continue,
+                    v => return Err(PipeError::Errno(v)),
+                },
+                v => return Ok(v as usize),
+            }
+        }
+    }
+
+    // read once, 0 means eof. Timeout when nothing to read within timeout
+    pub fn read_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, PipeError> {
+        let fd = self.fd;
+        io_until(fd, POLLIN, deadline(timeout), || unsafe {
+            libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len())
+        })
+    }
+
+    // fill the whole buf within timeout, TimeoutAfter and UnexpectedEof tell how many bytes read
+    pub fn read_exact_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<(), PipeError> {
+        let fd = self.fd;
+        let deadline = deadline(timeout);
+        let mut filled = 0;
+        while filled < buf.len() {
+            let rest = &mut buf[filled..];
+            match io_until(fd, POLLIN, deadline, || unsafe {
+                libc::read(fd, rest.as_mut_ptr() as *mut c_void, rest.len())
+            }) {
+                Ok(0) => return Err(PipeError::UnexpectedEof(filled)),
+                Ok(v) => filled += v,
+                Err(PipeError::Timeout) => return Err(PipeError::TimeoutAfter(filled)),
+                Err(e) => return Err(e),
+            }
         }
+        Ok(())
+    }
+}
+
+impl PipeWriter {
+    pub fn is_packet(&self) -> Result<bool, PipeError> {
+        is_packet(self.fd)
+    }
+
+    /*
+     * write message as one packet of a packet mode pipe, the write is atomic so it is never
+     * split or mixed with messages of other writers. an empty message is not sent.
+     * NotPacket when the pipe is not created by Pipe::packet
+     */
+    pub fn send(&mut self, message: &[u8]) -> Result<(), PipeError> {
+        if !self.is_packet()? {
+            return Err(PipeError::NotPacket);
+        }
+        if message.len() > PIPE_BUF {
+            return Err(PipeError::MessageTooLarge(message.len()));
+        }
+        loop {
+            match unsafe { libc::write(self.fd, message.as_ptr() as *const c_void, message.len()) }
+            {
+                -1 => match unsafe { *__errno_location() } {
+                    libc::EINTR => continue,
+                    v => return Err(PipeError::Errno(v)),
+                },
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // write once, may be partial. Timeout when the pipe is still full after timeout
+    pub fn write_timeout(&mut self, buf: &[u8], timeout: Duration) -> Result<usize, PipeError> {
+        let fd = self.fd;
+        io_until(fd, POLLOUT, deadline(timeout), || unsafe {
+            libc::write(fd, buf.as_ptr() as *const c_void, buf.len())
+        })
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len()) } {
+                -1 => match std::io::Error::last_os_error() {
+                    e if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    e => return Err(e),
+                },
+                v => return Ok(v as usize),
+            }
+        }
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        loop {
+            match unsafe { libc::write(self.fd, buf.as_ptr() as *const c_void, buf.len()) } {
+                -1 => match std::io::Error::last_os_error() {
+                    e if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    e => return Err(e),
+                },
+                v => return Ok(v as usize),
+            }
+        }
+    }
+
+    // nothing is buffered
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
 
@@ -64,69 +481,20 @@ impl Display for Pipe {
         )
     }
 }
+// N pipes as [Pipe; N], see Pipe::pipes
 #[macro_export]
 macro_rules! create_pipe {
-    (1) => {{
-        let mut pipe = [libc::c_int; 2];
-        match unsafe {
-            pipe(pipe.as_mut_ptr())
-        } {
-            -1 => None,
-            _  => Some(pipe)
-        }
-    }};
-    ($n: expr) => {{
-        if $n <= 0 {
-            None
-        } else {
-            let mut pipes: [[libc::c_int; 2]; $n] = [[0; 2]; $n];
-            let mut success = true;
-            for i in 0..$n as usize {
-                unsafe {
-                    match libc::pipe(pipes[i].as_mut_ptr()) {
-                        0 => {}
-                        _ => {
-                            success = false;
-                            break;
-                        }
-                    }
-                };
-            }
-            if success {
-                Some(pipes)
-            } else {
-                None
-            }
-        }
-    }};
+    ($n: expr) => {
+        $crate::Pipe::pipes::<{ $n }>()
+    };
 }
 
+// N pipes created with the flags of each, see Pipe::pipes2
 #[macro_export]
 macro_rules! create_pipe2 {
-    ($n: expr, $modes: expr) => {{
-        if $n != $modes.len() || $n == 0 {
-            None
-        } else {
-            let mut pipes: [[libc::c_int; 2]; $n] = [[0; 2]; $n];
-            let mut success = true;
-            for i in 0..$n as usize {
-                unsafe {
-                    match libc::pipe2(pipes[i].as_mut_ptr(), $modes[i]) {
-                        0 => {}
-                        _ => {
-                            success = false;
-                            break;
-                        }
-                    }
-                };
-            }
-            if success {
-                Some(pipes)
-            } else {
-                None
-            }
-        }
-    }};
+    ($n: expr, $modes: expr) => {
+        $crate::Pipe::pipes2::<{ $n }>($modes)
+    };
 }
 
 #[cfg(test)]
@@ -135,7 +503,7 @@ mod pipe {
 
     use libc::execl;
 
-    use crate::Close;
+    use crate::{Close, Pipe, PipeError, PipeReader, PipeSide, PipeWriter};
 
     #[test]
     fn test_execl() -> Result<(), Box<dyn Error>> {
@@ -150,12 +518,255 @@ mod pipe {
     #[test]
     fn test_create_pipe_macro() {
         let pipes1 = create_pipe!(1 + 1).unwrap();
-        let pipes2 = create_pipe!(1 & 1).unwrap();
+        let pipes2 = create_pipe!(1).unwrap();
         assert!(pipes1.len() == 2);
         assert!(pipes2.len() == 1);
-        let s = &pipes1.iter().flatten().map(|x| *x).collect::<Vec<i32>>()[..];
-        Close::close(s).unwrap();
-        let s1 = &pipes2.iter().flatten().map(|x| *x).collect::<Vec<i32>>()[..];
-        Close::close(s1).unwrap();
+        let [pipe] = create_pipe2!(1, [libc::O_NONBLOCK]).unwrap();
+        let flags = unsafe { libc::fcntl(pipe.get(PipeSide::Read), libc::F_GETFL) };
+        assert_ne!(flags & libc::O_NONBLOCK, 0);
+        let fds = pipe.into_raw_fds();
+        Close::close(&fds).unwrap();
+        assert_eq!(
+            create_pipe!(0).map(|x| x.len()),
+            Err(PipeError::Errno(libc::EINVAL))
+        );
+        // an unknown flag, the errno of pipe2 itself
+        assert_eq!(Pipe::pipe2(-1), Err(libc::EINVAL));
+        assert_eq!(
+            create_pipe2!(2, [0, -1]).map(|x| x.len()),
+            Err(PipeError::Errno(libc::EINVAL))
+        );
+    }
+
+    #[test]
+    fn test_create_pipe_failed() {
+        use crate::Fork;
+
+        // in a child, so the fd limit and the count of fds are not changed by other tests
+        let child = Fork::spawn(|| {
+            let fds = || {
+                std::fs::read_dir("/proc/self/fd")
+                    .unwrap()
+                    .map(|x| x.unwrap().file_name().to_str().unwrap().parse().unwrap())
+                    .collect::<Vec<libc::rlim_t>>()
+            };
+            let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+            // room for a few pipes only
+            limit.rlim_cur = fds().into_iter().max().unwrap() + 5;
+            unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) };
+            let before = fds().len();
+            match create_pipe!(1024) {
+                Err(PipeError::Errno(libc::EMFILE)) if fds().len() == before => 0,
+                _ => 1,
+            }
+        })
+        .unwrap();
+        let (_, status) = child.wait().unwrap();
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+
+    #[test]
+    fn test_pipe_side() {
+        let pipe = Pipe::pipe().unwrap();
+        let (read, write) = (pipe.get(PipeSide::Read), pipe.get(PipeSide::Write));
+        assert_eq!(
+            unsafe { libc::write(write, "x".as_ptr() as *const _, 1) },
+            1
+        );
+        let mut buf = [0u8; 1];
+        assert_eq!(
+            unsafe { libc::read(read, buf.as_mut_ptr() as *mut _, 1) },
+            1
+        );
+        assert_eq!(&buf, b"x");
+    }
+
+    #[test]
+    fn test_split() {
+        use std::io::{Read, Write};
+
+        let (mut reader, mut writer) = Pipe::pipe().unwrap().split();
+        writer.write_all(b"hello").unwrap();
+        // the reader see eof once the writer closed, so the writer is closed by itself
+        drop(writer);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+    }
+
+    #[test]
+    fn test_owned_fd() {
+        use std::{
+            io::{Read, Write},
+            os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd},
+        };
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        let mut reader = PipeReader::from(unsafe { OwnedFd::from_raw_fd(fds[0]) });
+        let mut writer = PipeWriter::from(unsafe { OwnedFd::from_raw_fd(fds[1]) });
+        assert_eq!(reader.as_raw_fd(), fds[0]);
+        writer.write_all(b"abc").unwrap();
+        let fd = writer.into_raw_fd();
+        assert_eq!(fd, fds[1]);
+        Close::close(&[fd]).unwrap();
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "abc");
+        let fd = OwnedFd::from(reader);
+        assert_eq!(fd.as_raw_fd(), fds[0]);
+    }
+
+    #[test]
+    fn test_capacity() {
+        use std::io::Write;
+
+        let pipe = Pipe::pipe().unwrap();
+        let max = Pipe::max_capacity().unwrap();
+        assert_eq!(pipe.capacity(), Ok(65536));
+        let size = pipe.set_capacity(max.min(1 << 20)).unwrap();
+        assert_eq!(pipe.capacity(), Ok(size));
+        assert!(size >= max.min(1 << 20));
+        // clamped to the max instead of EPERM
+        assert_eq!(pipe.set_capacity(usize::MAX), Ok(max));
+        let (reader, mut writer) = pipe.split();
+        assert_eq!(reader.available(), Ok(0));
+        writer.write_all(&[0u8; 5000]).unwrap();
+        assert_eq!(reader.available(), Ok(5000));
+        assert_eq!(writer.capacity(), Ok(max));
+        assert_eq!(writer.set_capacity(0), Err(PipeError::Errno(libc::EBUSY)));
+    }
+
+    #[test]
+    fn test_timeout() {
+        use std::{
+            io::Write,
+            time::{Duration, Instant},
+        };
+
+        let (mut reader, mut writer) = Pipe::pipe2(libc::O_NONBLOCK).unwrap().split();
+        let mut buf = [0u8; 8];
+        let start = Instant::now();
+        assert_eq!(
+            reader.read_timeout(&mut buf, Duration::from_millis(50)),
+            Err(PipeError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        writer.write_all(b"abc").unwrap();
+        assert_eq!(
+            reader.read_timeout(&mut buf, Duration::from_millis(50)),
+            Ok(3)
+        );
+        // fill the pipe, then the writer time out
+        let full = vec![0u8; writer.capacity().unwrap()];
+        assert_eq!(writer.write_timeout(&full, Duration::ZERO), Ok(full.len()));
+        assert_eq!(
+            writer.write_timeout(b"x", Duration::from_millis(10)),
+            Err(PipeError::Timeout)
+        );
+        // writable again once a whole page is free
+        let mut page = [0u8; 4096];
+        assert_eq!(reader.read_timeout(&mut page, Duration::ZERO), Ok(4096));
+        assert_eq!(writer.write_timeout(b"x", Duration::ZERO), Ok(1));
+    }
+
+    #[test]
+    fn test_read_exact_timeout() {
+        use std::{io::Write, time::Duration};
+
+        let (mut reader, mut writer) = Pipe::pipe2(libc::O_NONBLOCK).unwrap().split();
+        let child = std::thread::spawn(move || {
+            for part in [&b"hel"[..], b"lo", b" world"].iter() {
+                std::thread::sleep(Duration::from_millis(10));
+                writer.write_all(part).unwrap();
+            }
+        });
+        let mut buf = [0u8; 5];
+        reader
+            .read_exact_timeout(&mut buf, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        child.join().unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            reader.read_exact_timeout(&mut buf, Duration::MAX),
+            Err(PipeError::UnexpectedEof(6))
+        );
+    }
+
+    #[test]
+    fn test_read_exact_timeout_partial() {
+        use std::{io::Write, time::Duration};
+
+        let (mut reader, mut writer) = Pipe::pipe2(libc::O_NONBLOCK).unwrap().split();
+        writer.write_all(b"abc").unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            reader.read_exact_timeout(&mut buf, Duration::from_millis(10)),
+            Err(PipeError::TimeoutAfter(3))
+        );
+        assert_eq!(&buf[..3], b"abc");
+        // no overflow, read at once as the data is there
+        writer.write_all(b"d").unwrap();
+        assert_eq!(reader.read_timeout(&mut buf, Duration::MAX), Ok(1));
+        assert_eq!(writer.write_timeout(b"e", Duration::MAX), Ok(1));
+    }
+
+    #[test]
+    fn test_packet() {
+        use crate::Fork;
+
+        let pipe = Pipe::packet(libc::O_CLOEXEC).unwrap();
+        assert_eq!(pipe.is_packet(), Ok(true));
+        let (_, mut ordinary) = Pipe::pipe().unwrap().split();
+        assert_eq!(ordinary.is_packet(), Ok(false));
+        assert_eq!(ordinary.send(b"x"), Err(PipeError::NotPacket));
+        let (mut reader, mut writer) = pipe.split();
+        assert_eq!(writer.is_packet(), Ok(true));
+        // records of workers keep their boundaries
+        let workers = (1..=3)
+            .map(|i| {
+                Fork::spawn(|| {
+                    let message = vec![i as u8; i * 1000];
+                    match writer.send(&message) {
+                        Ok(_) => 0,
+                        Err(_) => 1,
+                    }
+                })
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            let (_, status) = worker.wait().unwrap();
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+        }
+        assert_eq!(
+            writer.send(&[0u8; libc::PIPE_BUF + 1]),
+            Err(PipeError::MessageTooLarge(libc::PIPE_BUF + 1))
+        );
+        writer.send(&[4u8; libc::PIPE_BUF]).unwrap();
+        drop(writer);
+        let mut buf = [0u8; libc::PIPE_BUF];
+        let mut sizes = Vec::new();
+        loop {
+            match reader.recv(&mut buf).unwrap() {
+                0 => break,
+                size => {
+                    let expect = match size {
+                        1000 => 1,
+                        2000 => 2,
+                        3000 => 3,
+                        libc::PIPE_BUF => 4,
+                        _ => panic!("unexpected packet of {} bytes", size),
+                    };
+                    assert!(buf[..size].iter().all(|x| *x == expect));
+                    sizes.push(size);
+                }
+            }
+        }
+        sizes.sort_unstable();
+        assert_eq!(sizes, vec![1000, 2000, 3000, libc::PIPE_BUF]);
     }
 }
@@ -0,0 +1,607 @@
+use std::{
+    fmt::Display,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use libc::{
+    __errno_location, _exit, c_int, c_uint, CLOSE_RANGE_CLOEXEC, O_CLOEXEC, STDERR_FILENO,
+    STDIN_FILENO, STDOUT_FILENO,
+};
+
+use crate::{
+    errno_name, report::ReportPipe, Close, Exec, ExecError, Fork, ForkError, PidfdFork, Pipe,
+    PipeReader, PipeWriter, ProcessHandle,
+};
+
+/*
+ * a | b | c:
+ * stdout of every stage is connected to stdin of the next one by a pipe,
+ * stdin of the first stage, stdout of the last stage and stderr of every stage are set by
+ * PipelineStdio. every fd created here is close on exec and above stdio, a child also mark
+ * every fd from 3 close on exec before exec, so it only keep its own stdio even the caller
+ * opened fds without O_CLOEXEC. the parent close the ends it do not hold once all stages spawned
+ */
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    stages: Vec<Exec>,
+    stdin: PipelineStdio,
+    stdout: PipelineStdio,
+    // index of stage, Inherit when missing
+    stderr: Vec<(usize, PipelineStdio)>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PipelineStdio {
+    #[default]
+    Inherit,
+    // /dev/null
+    Null,
+    // a pipe, the other end is kept in PipelineChild
+    Piped,
+    // duplicated before spawn, the caller still own it
+    Fd(c_int),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PipelineError {
+    NoStage,
+    // stderr set for a stage index not in the pipeline
+    NoSuchStage(usize),
+    // pipe, /dev/null or duplicate of PipelineStdio::Fd failed
+    StdioErrno(c_int),
+    ReportPipeErrno(c_int),
+    // the index of stage and the error, spawned stages are killed and reaped
+    ExecError(usize, ExecError),
+    Dup2Failed(usize, c_int),
+    CloseFailed(usize, c_int),
+    ForkFailed(usize, ForkError),
+    WaitFailed(usize, c_int),
+}
+
+impl Display for PipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipelineError::NoStage => f.write_str("pipeline has no stage!"),
+            PipelineError::NoSuchStage(i) => {
+                f.write_str(std::format!("pipeline has no stage {}!", i).as_str())
+            }
+            PipelineError::StdioErrno(v) => f.write_str(
+                std::format!(
+                    "create stdio of pipeline failed! errno: {} ({})",
+                    v,
+                    errno_name(*v)
+                )
+                .as_str(),
+            ),
+            PipelineError::ReportPipeErrno(v) => f.write_str(
+                std::format!(
+                    "child report pipe failed! errno: {} ({})",
+                    v,
+                    errno_name(*v)
+                )
+                .as_str(),
+            ),
+            PipelineError::ExecError(i, v) => {
+                f.write_str(std::format!("stage {}: {}", i, v).as_str())
+            }
+            PipelineError::Dup2Failed(i, v) => f.write_str(
+                std::format!(
+                    "stage {}: redirect stdio failed! errno: {} ({})",
+                    i,
+                    v,
+                    errno_name(*v)
+                )
+                .as_str(),
+            ),
+            PipelineError::CloseFailed(i, v) => f.write_str(
+                std::format!(
+                    "stage {}: close inherited fds failed! errno: {} ({})",
+                    i,
+                    v,
+                    errno_name(*v)
+                )
+                .as_str(),
+            ),
+            PipelineError::ForkFailed(i, v) => {
+                f.write_str(std::format!("stage {}: {}", i, v).as_str())
+            }
+            PipelineError::WaitFailed(i, v) => f.write_str(
+                std::format!(
+                    "stage {}: wait failed! errno: {} ({})",
+                    i,
+                    v,
+                    errno_name(*v)
+                )
+                .as_str(),
+            ),
+        }
+    }
+}
+
+// the running stages and the parent ends of Piped stdio
+#[derive(Debug)]
+pub struct PipelineChild {
+    pub stdin: Option<PipeWriter>,
+    pub stdout: Option<PipeReader>,
+    // by index of stage, Some for Piped stderr only
+    pub stderr: Vec<Option<PipeReader>>,
+    processes: Vec<ProcessHandle>,
+}
+
+// wait status of every stage, encoded as waitpid does
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PipelineStatus {
+    pub statuses: Vec<c_int>,
+}
+
+// report stages sent by the child
+const STAGE_DUP2: u32 = 0;
+const STAGE_EXEC: u32 = 1;
+const STAGE_CLOSE: u32 = 2;
+
+fn errno() -> c_int {
+    unsafe { *__errno_location() }
+}
+
+// above stdio, so redirect one of 0 1 2 never overwrite the source of another
+fn duplicate(fd: c_int) -> Result<OwnedFd, PipelineError> {
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3) } {
+        -1 => Err(PipelineError::StdioErrno(errno())),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+// got 0 1 or 2 when the parent closed it, move it above stdio as duplicate do
+fn above_stdio(fd: OwnedFd) -> Result<OwnedFd, PipelineError> {
+    match fd.as_raw_fd() {
+        0..=2 => duplicate(fd.as_raw_fd()),
+        _ => Ok(fd),
+    }
+}
+
+fn pipe() -> Result<(PipeReader, PipeWriter), PipelineError> {
+    let (reader, writer) = Pipe::pipe2(O_CLOEXEC)
+        .map(Pipe::split)
+        .map_err(PipelineError::StdioErrno)?;
+    Ok((
+        PipeReader::from(above_stdio(OwnedFd::from(reader))?),
+        PipeWriter::from(above_stdio(OwnedFd::from(writer))?),
+    ))
+}
+
+fn dev_null() -> Result<OwnedFd, PipelineError> {
+    match unsafe { libc::open("/dev/null\0".as_ptr() as *const _, libc::O_RDWR | O_CLOEXEC) } {
+        -1 => Err(PipelineError::StdioErrno(errno())),
+        fd => above_stdio(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+// run in the child, dup2 keep close on exec when fd is already target so clear it
+fn redirect(fd: c_int, target: c_int) -> Result<(), c_int> {
+    let result = if fd == target {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, 0) }
+    } else {
+        unsafe { libc::dup2(fd, target) }
+    };
+    match result {
+        -1 => Err(errno()),
+        _ => Ok(()),
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    // append a stage, its stdin read the stdout of the previous stage
+    pub fn stage(mut self, program: Exec) -> Pipeline {
+        self.stages.push(program);
+        self
+    }
+
+    // stdin of the first stage
+    pub fn stdin(mut self, stdio: PipelineStdio) -> Pipeline {
+        self.stdin = stdio;
+        self
+    }
+
+    // stdout of the last stage
+    pub fn stdout(mut self, stdio: PipelineStdio) -> Pipeline {
+        self.stdout = stdio;
+        self
+    }
+
+    // stderr of the stage at index
+    pub fn stderr(mut self, index: usize, stdio: PipelineStdio) -> Pipeline {
+        self.stderr.push((index, stdio));
+        self
+    }
+
+    pub fn spawn(&self) -> Result<PipelineChild, PipelineError> {
+        if self.stages.is_empty() {
+            return Err(PipelineError::NoStage);
+        }
+        let last = self.stages.len() - 1;
+        if let Some((index, _)) = self.stderr.iter().find(|(i, _)| *i > last) {
+            return Err(PipelineError::NoSuchStage(*index));
+        }
+        // the child of fork should not allocate, so prepare everything here
+        let programs = self
+            .stages
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x.prepare().map_err(|e| PipelineError::ExecError(i, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        // fds the children redirect from, closed when spawn return
+        let mut sources: Vec<OwnedFd> = Vec::new();
+        let mut child = PipelineChild {
+            stdin: None,
+            stdout: None,
+            stderr: (0..self.stages.len()).map(|_| None).collect(),
+            processes: Vec::with_capacity(self.stages.len()),
+        };
+        let mut source =
+            |stdio: PipelineStdio, read: bool| -> Result<Option<c_int>, PipelineError> {
+                let fd = match stdio {
+                    PipelineStdio::Inherit => return Ok(None),
+                    PipelineStdio::Null => dev_null()?,
+                    PipelineStdio::Fd(fd) => duplicate(fd)?,
+                    PipelineStdio::Piped => {
+                        let (reader, writer) = pipe()?;
+                        if read {
+                            child.stdin = Some(writer);
+                            OwnedFd::from(reader)
+                        } else {
+                            OwnedFd::from(writer)
+                        }
+                    }
+                };
+                let raw = fd.as_raw_fd();
+                sources.push(fd);
+                Ok(Some(raw))
+            };
+        // [stdin, stdout, stderr] of every stage, None to inherit
+        let mut stdio = vec![[None; 3]; self.stages.len()];
+        stdio[0][0] = source(self.stdin, true)?;
+        if self.stdout != PipelineStdio::Piped {
+            stdio[last][1] = source(self.stdout, false)?;
+        }
+        for (index, stderr) in &self.stderr {
+            if *stderr != PipelineStdio::Piped {
+                stdio[*index][2] = source(*stderr, false)?;
+            }
+        }
+        if self.stdout == PipelineStdio::Piped {
+            let (reader, writer) = pipe()?;
+            stdio[last][1] = Some(writer.as_raw_fd());
+            child.stdout = Some(reader);
+            sources.push(OwnedFd::from(writer));
+        }
+        for (index, stderr) in &self.stderr {
+            if *stderr == PipelineStdio::Piped {
+                let (reader, writer) = pipe()?;
+                stdio[*index][2] = Some(writer.as_raw_fd());
+                child.stderr[*index] = Some(reader);
+                sources.push(OwnedFd::from(writer));
+            }
+        }
+        for i in 0..last {
+            let (reader, writer) = pipe()?;
+            stdio[i][1] = Some(writer.as_raw_fd());
+            stdio[i + 1][0] = Some(reader.as_raw_fd());
+            sources.push(OwnedFd::from(reader));
+            sources.push(OwnedFd::from(writer));
+        }
+        for (i, program) in programs.iter().enumerate() {
+            let mut report = match ReportPipe::new() {
+                Ok(report) => report,
+                Err(v) => {
+                    child.abort();
+                    return Err(PipelineError::ReportPipeErrno(v));
+                }
+            };
+            let handle = match Fork::fork_pidfd() {
+                Ok(PidfdFork::Parent(handle)) => handle,
+                Ok(PidfdFork::Children(_)) => {
+                    report.close_read();
+                    for (fd, target) in
+                        stdio[i]
+                            .iter()
+                            .zip(&[STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO])
+                    {
+                        if let Some(fd) = fd {
+                            if let Err(v) = redirect(*fd, *target) {
+                                report.send(STAGE_DUP2, v);
+                                unsafe { _exit(127) };
+                            }
+                        }
+                    }
+                    // fds the caller opened without O_CLOEXEC, the report pipe is close on exec
+                    if let Err(Close::CloseErrno(v)) | Err(Close::FCloseErrno(v)) =
+                        Close::close_range(3, c_uint::MAX, CLOSE_RANGE_CLOEXEC)
+                    {
+                        report.send(STAGE_CLOSE, v);
+                        unsafe { _exit(127) };
+                    }
+                    report.send(STAGE_EXEC, program.exec());
+                    unsafe { _exit(127) };
+                }
+                Err(e) => {
+                    child.abort();
+                    return Err(PipelineError::ForkFailed(i, e));
+                }
+            };
+            child.processes.push(handle);
+            report.close_write();
+            // eof means the stage exec successfully
+            match report.recv() {
+                Ok(None) => (),
+                Ok(Some((stage, v))) => {
+                    child.abort();
+                    return Err(match stage {
+                        STAGE_DUP2 => PipelineError::Dup2Failed(i, v),
+                        STAGE_CLOSE => PipelineError::CloseFailed(i, v),
+                        _ => PipelineError::ExecError(i, ExecError::Errno(v)),
+                    });
+                }
+                Err(v) => {
+                    child.abort();
+                    return Err(PipelineError::ReportPipeErrno(v));
+                }
+            }
+        }
+        Ok(child)
+    }
+}
+
+impl PipelineChild {
+    // pid of every stage
+    pub fn pids(&self) -> Vec<libc::pid_t> {
+        self.processes.iter().map(|x| x.pid()).collect()
+    }
+
+    pub fn kill(&self, signal: c_int) {
+        for process in &self.processes {
+            process.send_signal(signal).ok();
+        }
+    }
+
+    /*
+     * wait every stage, the Piped stdin is closed first so the first stage see eof.
+     * read stdout and stderr before, or a stage may block on a full pipe
+     */
+    pub fn wait(&mut self) -> Result<PipelineStatus, PipelineError> {
+        self.stdin = None;
+        let mut statuses = Vec::with_capacity(self.processes.len());
+        for (i, process) in self.processes.iter().enumerate() {
+            match process.wait(0) {
+                Ok((_, status)) => statuses.push(status),
+                Err(crate::Wait::WaitFailure(v)) => return Err(PipelineError::WaitFailed(i, v)),
+                Err(_) => return Err(PipelineError::WaitFailed(i, libc::ECHILD)),
+            }
+        }
+        Ok(PipelineStatus { statuses })
+    }
+
+    // spawn failed, the spawned stages should not outlive the pipeline
+    fn abort(&mut self) {
+        self.kill(libc::SIGKILL);
+        for process in &self.processes {
+            process.wait(0).ok();
+        }
+        self.processes.clear();
+    }
+}
+
+// the stages are reaped when not waited, by pidfd so a stage already waited is not touched
+impl Drop for PipelineChild {
+    fn drop(&mut self) {
+        // close the parent ends first, or a stage may block on them forever
+        self.stdin = None;
+        self.stdout = None;
+        self.stderr.clear();
+        for process in &self.processes {
+            process.wait(0).ok();
+        }
+    }
+}
+
+// exit code as sh report it, 128 + signal when killed
+fn code(status: c_int) -> c_int {
+    if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        libc::WEXITSTATUS(status)
+    }
+}
+
+impl PipelineStatus {
+    pub fn codes(&self) -> Vec<c_int> {
+        self.statuses.iter().map(|x| code(*x)).collect()
+    }
+
+    // code of the last stage, as sh without pipefail
+    pub fn code(&self) -> c_int {
+        self.statuses.last().map(|x| code(*x)).unwrap_or(0)
+    }
+
+    // code of the last failed stage or 0, as set -o pipefail
+    pub fn pipefail(&self) -> c_int {
+        self.codes()
+            .into_iter()
+            .rev()
+            .find(|x| *x != 0)
+            .unwrap_or(0)
+    }
+
+    pub fn success(&self) -> bool {
+        self.pipefail() == 0
+    }
+}
+
+#[cfg(test)]
+mod pipeline {
+    use std::{
+        io::{Read, Write},
+        time::Duration,
+    };
+
+    use crate::{Exec, ExecError, Pipeline, PipelineError, PipelineStdio};
+
+    fn sh(script: &str) -> Exec {
+        Exec::new("/bin/sh").args(&["-c", script])
+    }
+
+    #[test]
+    fn pipeline_three_stages() {
+        let mut child = Pipeline::new()
+            .stage(sh("printf 'b\\na\\nc\\n'"))
+            .stage(Exec::new("sort").search())
+            .stage(Exec::new("tr").search().args(&["a-z", "A-Z"]))
+            .stdout(PipelineStdio::Piped)
+            .spawn()
+            .unwrap();
+        let mut output = String::new();
+        child
+            .stdout
+            .as_mut()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output, "A\nB\nC\n");
+        let status = child.wait().unwrap();
+        assert_eq!(status.codes(), vec![0, 0, 0]);
+        assert!(status.success());
+    }
+
+    #[test]
+    fn pipeline_stdio() {
+        let mut child = Pipeline::new()
+            .stage(sh("cat; echo err >&2"))
+            .stage(sh("wc -c"))
+            .stdin(PipelineStdio::Piped)
+            .stdout(PipelineStdio::Piped)
+            .stderr(0, PipelineStdio::Piped)
+            .stderr(1, PipelineStdio::Null)
+            .spawn()
+            .unwrap();
+        child.stdin.as_mut().unwrap().write_all(b"hello").unwrap();
+        // the stdin is closed by wait, close it here to read before
+        child.stdin = None;
+        let mut output = String::new();
+        child
+            .stdout
+            .as_mut()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output.trim(), "5");
+        let mut error = String::new();
+        child.stderr[0]
+            .as_mut()
+            .unwrap()
+            .read_to_string(&mut error)
+            .unwrap();
+        assert_eq!(error, "err\n");
+        assert!(child.stderr[1].is_none());
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn pipeline_pipefail() {
+        let status = Pipeline::new()
+            .stage(sh("exit 3"))
+            .stage(sh("kill -9 $$"))
+            .stage(sh("exit 0"))
+            .stdout(PipelineStdio::Null)
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert_eq!(status.codes(), vec![3, 128 + libc::SIGKILL, 0]);
+        assert_eq!(status.code(), 0);
+        assert_eq!(status.pipefail(), 128 + libc::SIGKILL);
+        assert!(!status.success());
+    }
+
+    #[test]
+    fn pipeline_no_leak() {
+        // cat only see eof when no other process hold a write end of its stdin
+        let mut child = Pipeline::new()
+            .stage(sh("true"))
+            .stage(sh("cat"))
+            .stage(sh("cat"))
+            .stdin(PipelineStdio::Null)
+            .stdout(PipelineStdio::Piped)
+            .spawn()
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let stdout = child.stdout.as_mut().unwrap();
+        assert_eq!(
+            stdout.read_timeout(&mut buf, Duration::from_secs(10)),
+            Ok(0)
+        );
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn pipeline_exec_failed() {
+        let result = Pipeline::new()
+            .stage(sh("sleep 100"))
+            .stage(Exec::new("/libc_tools/not/exist"))
+            .spawn();
+        assert_eq!(
+            result.unwrap_err(),
+            PipelineError::ExecError(1, ExecError::Errno(libc::ENOENT))
+        );
+        assert_eq!(Pipeline::new().spawn().unwrap_err(), PipelineError::NoStage);
+    }
+
+    #[test]
+    fn pipeline_no_such_stage() {
+        let result = Pipeline::new()
+            .stage(sh("exit 0"))
+            .stderr(1, PipelineStdio::Null)
+            .spawn();
+        assert_eq!(result.unwrap_err(), PipelineError::NoSuchStage(1));
+    }
+
+    #[test]
+    fn pipeline_close_inherited() {
+        // opened without O_CLOEXEC, every stage should not see it
+        let fd = unsafe { libc::open("/dev/null\0".as_ptr() as *const _, libc::O_RDONLY) };
+        assert!(fd > 2);
+        let script = std::format!("test ! -e /proc/$$/fd/{}", fd);
+        let status = Pipeline::new()
+            .stage(sh(script.as_str()))
+            .stage(sh(script.as_str()))
+            .stdin(PipelineStdio::Null)
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap();
+        unsafe { libc::close(fd) };
+        assert_eq!(status.codes(), vec![0, 0]);
+    }
+
+    #[test]
+    fn pipeline_drop_reap() {
+        // cat exit once the Piped stdin is closed by drop
+        let child = Pipeline::new()
+            .stage(Exec::new("/bin/cat"))
+            .stage(sh("exit 0"))
+            .stdin(PipelineStdio::Piped)
+            .spawn()
+            .unwrap();
+        let pids = child.pids();
+        drop(child);
+        for pid in pids {
+            let mut status = 0;
+            assert_eq!(
+                unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) },
+                -1
+            );
+        }
+    }
+}
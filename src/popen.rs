@@ -1,28 +1,68 @@
 use libc::{
-    __errno_location, _exit, c_int, c_void, execl, fclose, fdopen, pipe, pipe2, read, socketpair,
-    AF_UNIX, FILE, O_NONBLOCK, SOCK_STREAM, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO,
+    __errno_location, _exit, c_int, c_void, fclose, fdopen, pipe, pipe2, read, socketpair, AF_UNIX,
+    FILE, O_NONBLOCK, SOCK_STREAM, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO,
+};
+use std::{
+    ffi::{CString, NulError},
+    os::unix::io::{IntoRawFd, OwnedFd},
 };
-use std::ffi::{CString, NulError};
 
 use crate::{
-    create_pipe, create_pipe2, dup::DupError, wait::Wait, Close, Dup, Fork, ForkPid,
-    SocketPairError,
+    create_pipe, create_pipe2, dup::DupError, fork::set_parent_death_signal, report::ReportPipe,
+    wait::Wait, Close, Env, Exec, ExecError, FileActions, Fork, ForkError, NamespaceStage,
+    Namespaces, PidfdError, PidfdFork, Pipe, PipeError, PipeSide, ProcessHandle, SocketPairError,
+    Spawn, SpawnAttributes, SpawnBackend, SpawnError,
 };
 
-#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+// run in the child after stdio redirected and before exec, return errno when failed
+type PreExecHook = Box<dyn FnMut() -> Result<(), c_int> + Send + Sync>;
+
+#[derive(Default)]
+struct PreExecHooks(Vec<PreExecHook>);
+
+impl std::fmt::Debug for PreExecHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(std::format!("{} pre exec hooks", self.0.len()).as_str())
+    }
+}
+
+#[derive(Debug)]
 pub struct Popen {
     pub arg: String,
+    pub program: Exec,
     pub stdin: *mut FILE,
     pub stdout: *mut FILE,
     pub stderr: *mut FILE,
     pub pid: Option<c_int>,
+    pub process: Option<ProcessHandle>,
+    pub backend: SpawnBackend,
+    pub current_dir: Option<String>,
+    pub namespaces: Option<Namespaces>,
+    pub attributes: SpawnAttributes,
+    pub parent_death_signal: Option<c_int>,
+    pre_exec: PreExecHooks,
 }
 
+// stages reported by the child through the report pipe with the errno
+const STAGE_DUP2: u32 = 0;
+const STAGE_CLOSE: u32 = 1;
+const STAGE_CHDIR: u32 = 2;
+const STAGE_PRE_EXEC: u32 = 3;
+const STAGE_EXEC: u32 = 4;
+const STAGE_SETPGID: u32 = 5;
+const STAGE_SETSID: u32 = 6;
+const STAGE_PARENT_DEATH_SIGNAL: u32 = 7;
+// STAGE_NAMESPACE + NamespaceStage
+const STAGE_NAMESPACE: u32 = 16;
+
+// posix_spawn move the image of Exec::from_memory here, so one close_from keep it
+const MEMFD_FILENO: c_int = 3;
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum PopenError {
-    PipeCreateFailed,
+    PipeCreateFailed(PipeError),
     ExecArgFailed(c_int),
-    ForkFailed,
+    ForkFailed(ForkError),
     PipeRedirectFailed(c_int),
     Dup2Errno(DupError),
     FdOpenErrno(c_int),
@@ -30,14 +70,27 @@ pub enum PopenError {
     SocketPairError(SocketPairError),
     CreateRedirectError(c_int),
     CStringParesError(NulError),
+    PidfdError(PidfdError),
+    SpawnError(SpawnError),
+    PreExecFailed(c_int),
+    ChdirFailed(c_int),
+    ReportPipeErrno(c_int),
+    BackendUnsupported(&'static str),
+    NamespaceFailed(NamespaceStage, c_int),
+    SetpgidFailed(c_int),
+    SetsidFailed(c_int),
+    NoProcessGroup,
+    KillGroupFailed(c_int),
+    ParentDeathSignalFailed(c_int),
+    ExecError(ExecError),
 }
 
 impl PopenError {
     fn to_string(&self) -> String {
         match self {
-            Self::PipeCreateFailed => "create pipe failed!".to_string(),
-            Self::ExecArgFailed(code) => std::format!("exec arg failed! exit code: {}", code),
-            Self::ForkFailed => "fork failed!".to_string(),
+            Self::PipeCreateFailed(v) => std::format!("create pipe failed! {}", v),
+            Self::ExecArgFailed(v) => std::format!("exec arg failed! errno: {}", v),
+            Self::ForkFailed(v) => std::format!("{}", v),
             Self::PipeRedirectFailed(v) => {
                 std::format!("redirect std(in|out|err) to pipe failed! code: {}", v)
             }
@@ -60,6 +113,25 @@ impl PopenError {
             Self::CStringParesError(n) => {
                 std::format!("parse {:<.20} failed!", n.to_string())
             }
+            Self::PidfdError(v) => std::format!("{}", v),
+            Self::SpawnError(v) => std::format!("{}", v),
+            Self::PreExecFailed(v) => std::format!("pre exec hook failed! errno: {}", v),
+            Self::ChdirFailed(v) => std::format!("change directory failed! errno: {}", v),
+            Self::ReportPipeErrno(v) => std::format!("child report pipe failed! errno: {}", v),
+            Self::BackendUnsupported(v) => {
+                std::format!("{} is not supported by the spawn backend!", v)
+            }
+            Self::NamespaceFailed(stage, v) => {
+                std::format!("enter namespaces failed at {}! errno: {}", stage, v)
+            }
+            Self::SetpgidFailed(v) => std::format!("setpgid failed! errno: {}", v),
+            Self::SetsidFailed(v) => std::format!("setsid failed! errno: {}", v),
+            Self::NoProcessGroup => "the child do not lead a process group or session!".to_string(),
+            Self::KillGroupFailed(v) => std::format!("kill process group failed! errno: {}", v),
+            Self::ParentDeathSignalFailed(v) => {
+                std::format!("set parent death signal failed! errno: {}", v)
+            }
+            Self::ExecError(v) => std::format!("{}", v),
         }
     }
 }
@@ -88,7 +160,9 @@ fn socket_pipe() -> Result<[[c_int; 2]; 2], PopenError> {
         (-1, 0) => Err(PopenError::SocketPairError(SocketPairError::SocketErrno(
             unsafe { *__errno_location() },
         ))),
-        (0, -1) => Err(PopenError::PipeCreateFailed),
+        (0, -1) => Err(PopenError::PipeCreateFailed(PipeError::Errno(unsafe {
+            *__errno_location()
+        }))),
         (-1, -1) => Err(PopenError::CreateRedirectError(unsafe {
             *__errno_location()
         })),
@@ -100,62 +174,288 @@ fn socket_pipe() -> Result<[[c_int; 2]; 2], PopenError> {
 //BUG:opened fd do not closed!
 #[deprecated(note = "do not use!")]
 impl Popen {
+    // run arg by /bin/sh -c, quote words by Quote::join_str or use command instead
     pub fn arg(arg: &str) -> Box<Popen> {
+        Popen::shell("/bin/sh", arg)
+    }
+
+    // exec program searched in PATH with args as is, nothing is parsed by a shell
+    pub fn command(program: &str, args: &[&str]) -> Box<Popen> {
+        Popen::new(Exec::new(program).search().args(args))
+    }
+
+    // run script by shell -c, the shell is searched in PATH when it has no slash
+    pub fn shell(shell: &str, script: &str) -> Box<Popen> {
+        let mut popen = Popen::new(Exec::new(shell).search().args(&["-c", script]));
+        popen.arg = String::from(script);
+        popen
+    }
+
+    // run program directly with the same stdio pipes, eg: an image from Exec::from_memory
+    pub fn new(program: Exec) -> Box<Popen> {
         Box::new(Popen {
-            arg: String::from(arg),
-            stdin: 0 as *mut FILE,
-            stdout: 0 as *mut FILE,
-            stderr: 0 as *mut FILE,
+            arg: String::new(),
+            program,
+            stdin: std::ptr::null_mut(),
+            stdout: std::ptr::null_mut(),
+            stderr: std::ptr::null_mut(),
             pid: None,
+            process: None,
+            backend: SpawnBackend::default(),
+            current_dir: None,
+            namespaces: None,
+            attributes: SpawnAttributes::default(),
+            parent_death_signal: None,
+            pre_exec: PreExecHooks::default(),
         })
     }
+    pub fn backend(mut self: Box<Popen>, backend: SpawnBackend) -> Box<Popen> {
+        self.backend = backend;
+        self
+    }
+
+    // environment of the program, lowered to envp before fork
+    pub fn env(mut self: Box<Popen>, env: Env) -> Box<Popen> {
+        self.program = std::mem::take(&mut self.program).environment(env);
+        self
+    }
+
+    // the child change to the directory before pre exec hooks
+    pub fn current_dir(mut self: Box<Popen>, path: &str) -> Box<Popen> {
+        self.current_dir = Some(String::from(path));
+        self
+    }
+
+    // with a new pid namespace, pid and process refer to the relay outside the namespace
+    pub fn namespaces(mut self: Box<Popen>, namespaces: Namespaces) -> Box<Popen> {
+        self.namespaces = Some(namespaces);
+        self
+    }
+
+    // the child lead a new process group, so kill_group reach everything it started
+    pub fn process_group(mut self: Box<Popen>) -> Box<Popen> {
+        self.attributes.process_group = true;
+        self
+    }
+
+    // the child lead a new session without controlling terminal, also a new process group
+    pub fn session(mut self: Box<Popen>) -> Box<Popen> {
+        self.attributes.session = true;
+        self
+    }
+
+    // the child get signal when the thread calling exec exit, not supported by posix_spawn
+    pub fn parent_death_signal(mut self: Box<Popen>, signal: c_int) -> Box<Popen> {
+        self.parent_death_signal = Some(signal);
+        self
+    }
+
+    /// hooks run in registered order in the forked child, an error stop the child and
+    /// exec return PopenError::PreExecFailed with the errno.
+    /// fds from 3 are close on exec when hooks run, clear FD_CLOEXEC to pass one to the program.
+    /// the posix_spawn backend can not run hooks
+    ///
+    /// # Safety
+    /// the child may be created by clone3 in a multithread process,
+    /// so hooks should only call async signal safe functions and never allocate
+    pub unsafe fn pre_exec<F>(mut self: Box<Popen>, hook: F) -> Box<Popen>
+    where
+        F: FnMut() -> Result<(), c_int> + Send + Sync + 'static,
+    {
+        self.pre_exec.0.push(Box::new(hook));
+        self
+    }
+
     pub fn exec(mut self: Box<Popen>) -> Result<Box<Popen>, PopenError> {
+        if self.backend == SpawnBackend::PosixSpawn {
+            if !self.pre_exec.0.is_empty() {
+                return Err(PopenError::BackendUnsupported("pre exec hook"));
+            }
+            if self.current_dir.is_some() {
+                return Err(PopenError::BackendUnsupported("current dir"));
+            }
+            if self.namespaces.is_some() {
+                return Err(PopenError::BackendUnsupported("namespaces"));
+            }
+            if self.parent_death_signal.is_some() {
+                return Err(PopenError::BackendUnsupported("parent death signal"));
+            }
+        }
         // let [sv, fd] = socket_pipe()?;
-        let [stdout, stdin] = create_pipe!(2).ok_or(PopenError::PipeCreateFailed)?;
-        let [stderr] = create_pipe2!(1, [O_NONBLOCK]).ok_or(PopenError::PipeCreateFailed)?;
-        match Fork::fork() {
-            ForkPid::Parent((_, children)) => {
-                println!("{}", self.as_ref() as *const Popen as *const i32 as i32);
-                self.pid = Some(children);
-                Close::close(&[stdin[0], stdout[1], stderr[1]])
-                    .or_else(|x| Err(PopenError::CloseError(x)))?;
-                let r = CString::new("r").or_else(|x| Err(PopenError::CStringParesError(x)))?;
-                let w = CString::new("w").or_else(|x| Err(PopenError::CStringParesError(x)))?;
-                self.stdin = unsafe { fdopen(stdin[1], w.as_ptr()) };
-                self.stdout = unsafe { fdopen(stdout[0], r.as_ptr()) };
-                self.stderr = unsafe { fdopen(stderr[0], r.as_ptr()) };
-                Ok(self)
+        // the pipes own their fds until the child is created, so every error below close them
+        let [stdout_pipe, stdin_pipe] = create_pipe!(2).map_err(PopenError::PipeCreateFailed)?;
+        let [stderr_pipe] = create_pipe2!(1, [O_NONBLOCK]).map_err(PopenError::PipeCreateFailed)?;
+        let fds = |pipe: &Pipe| [pipe.get(PipeSide::Read), pipe.get(PipeSide::Write)];
+        let (stdout, stdin, stderr) = (fds(&stdout_pipe), fds(&stdin_pipe), fds(&stderr_pipe));
+        let mut actions = FileActions::new();
+        actions
+            .dup2(stdout[1], STDOUT_FILENO)
+            .dup2(stderr[1], STDERR_FILENO)
+            .dup2(stdin[0], STDIN_FILENO);
+        for fd in &[
+            stdout[0], stdout[1], stdin[0], stdin[1], stderr[0], stderr[1],
+        ] {
+            actions.close(*fd);
+        }
+        // the child of fork_pidfd should not allocate, so prepare all strings here
+        let program = self.program.prepare().map_err(PopenError::ExecError)?;
+        // the child keep stdio only, whatever the parent opened without O_CLOEXEC
+        let image = match (self.backend, program.memfd()) {
+            // posix_spawn exec the image by its path, so the memfd is kept open at a fixed fd
+            (SpawnBackend::PosixSpawn, Some(memfd)) => {
+                actions
+                    .dup2(memfd, MEMFD_FILENO)
+                    .close_from(MEMFD_FILENO + 1);
+                Some(
+                    CString::new(std::format!("/proc/self/fd/{}", MEMFD_FILENO))
+                        .map_err(PopenError::CStringParesError)?,
+                )
             }
-            // socket provide
-            ForkPid::Children(_) => {
-                println!("{}", self.as_ref() as *const Popen as *const i32 as i32);
-                Dup::dup2s(
-                    &[stdout[1], stderr[1], STDIN_FILENO],
-                    &[STDOUT_FILENO, STDERR_FILENO, stdin[0]],
+            _ => {
+                actions.close_from(3);
+                None
+            }
+        };
+        let current_dir = self
+            .current_dir
+            .as_ref()
+            .map(|x| CString::new(x.as_str()))
+            .transpose()
+            .map_err(PopenError::CStringParesError)?;
+        let namespaces = self.namespaces.as_ref().map(|x| x.prepare());
+        let parent = unsafe { libc::getpid() };
+        let mut report = ReportPipe::new().map_err(PopenError::ReportPipeErrno)?;
+        let handle = match self.backend {
+            SpawnBackend::Fork => match Fork::fork_pidfd().map_err(PopenError::ForkFailed)? {
+                PidfdFork::Parent(handle) => handle,
+                // socket provide
+                PidfdFork::Children(_) => {
+                    report.close_read();
+                    let fail = |stage: u32, v: c_int| -> ! {
+                        report.send(stage, v);
+                        unsafe { _exit(127) }
+                    };
+                    if let Some(signal) = self.parent_death_signal {
+                        if let Err(v) = set_parent_death_signal(signal, parent) {
+                            fail(STAGE_PARENT_DEATH_SIGNAL, v);
+                        }
+                    }
+                    // before namespaces, so init and workload of a pid namespace join the group
+                    match self.attributes.apply() {
+                        Err(SpawnError::SetpgidErrno(v)) => fail(STAGE_SETPGID, v),
+                        Err(SpawnError::SetsidErrno(v)) => fail(STAGE_SETSID, v),
+                        _ => (),
+                    }
+                    if let Some(namespaces) = &namespaces {
+                        let fds = [
+                            stdout[0],
+                            stdout[1],
+                            stdin[0],
+                            stdin[1],
+                            stderr[0],
+                            stderr[1],
+                            report.write_fd(),
+                        ];
+                        if let Err((stage, v)) = namespaces.enter(&fds) {
+                            fail(STAGE_NAMESPACE + stage as u32, v);
+                        }
+                    }
+                    match actions.apply() {
+                        Err(SpawnError::Dup2Errno(DupError::Errno(v))) => fail(STAGE_DUP2, v),
+                        Err(SpawnError::CloseError(Close::CloseErrno(v))) => fail(STAGE_CLOSE, v),
+                        _ => (),
+                    }
+                    if let Some(dir) = &current_dir {
+                        if unsafe { libc::chdir(dir.as_ptr()) } == -1 {
+                            fail(STAGE_CHDIR, unsafe { *__errno_location() });
+                        }
+                    }
+                    for hook in self.pre_exec.0.iter_mut() {
+                        if let Err(v) = hook() {
+                            fail(STAGE_PRE_EXEC, v);
+                        }
+                    }
+                    fail(STAGE_EXEC, program.exec());
+                }
+            },
+            SpawnBackend::PosixSpawn => {
+                let pid = Spawn::posix_spawn(
+                    image.as_deref().unwrap_or_else(|| program.program()),
+                    program.argv(),
+                    program.envp(),
+                    &actions,
+                    &self.attributes,
                 )
-                .unwrap();
-                Close::close(&[
-                    stdout[0], stdout[1], stdin[0], stdin[1], stderr[0], stderr[1],
-                ])
-                .unwrap();
-                let path = CString::new("/bin/sh").unwrap();
-                let sh = CString::new("sh").unwrap();
-                let exec = CString::new("-c").unwrap();
-                let zsh = CString::new("zsh").unwrap();
-                let arg = CString::new(self.arg.clone()).unwrap();
-                unsafe {
-                    _exit(execl(
-                        path.as_ptr(),
-                        sh.as_ptr(),
-                        exec.as_ptr(),
-                        zsh.as_ptr(),
-                        exec.as_ptr(),
-                        arg.as_ptr(),
-                        0,
-                    ))
-                };
+                .map_err(PopenError::SpawnError)?;
+                // the child is not reaped yet, so the pid can not be reused before pidfd_open
+                match ProcessHandle::open(pid) {
+                    Ok(handle) => handle,
+                    Err(e) => {
+                        unsafe { libc::kill(pid, libc::SIGKILL) };
+                        while let Err(Wait::WaitFailure(libc::EINTR)) = Wait::children_with(pid, 0)
+                        {
+                        }
+                        return Err(PopenError::PidfdError(e));
+                    }
+                }
             }
-            ForkPid::None => Err(PopenError::ForkFailed),
+        };
+        self.pid = Some(handle.pid());
+        self.process = Some(handle);
+        report.close_write();
+        // keep the parent ends, the child ends are closed as the other halves dropped
+        let stdin = OwnedFd::from(stdin_pipe.split().1);
+        let stdout = OwnedFd::from(stdout_pipe.split().0);
+        let stderr = OwnedFd::from(stderr_pipe.split().0);
+        // eof means the child exec successfully, the child is reaped when self dropped
+        if let Some((stage, v)) = report.recv().map_err(PopenError::ReportPipeErrno)? {
+            return Err(match stage {
+                STAGE_DUP2 => PopenError::Dup2Errno(DupError::Errno(v)),
+                STAGE_CLOSE => PopenError::CloseError(Close::CloseErrno(v)),
+                STAGE_CHDIR => PopenError::ChdirFailed(v),
+                STAGE_PRE_EXEC => PopenError::PreExecFailed(v),
+                STAGE_SETPGID => PopenError::SetpgidFailed(v),
+                STAGE_SETSID => PopenError::SetsidFailed(v),
+                STAGE_PARENT_DEATH_SIGNAL => PopenError::ParentDeathSignalFailed(v),
+                _ if stage >= STAGE_NAMESPACE => {
+                    match NamespaceStage::from_u32(stage - STAGE_NAMESPACE) {
+                        Some(stage) => PopenError::NamespaceFailed(stage, v),
+                        None => PopenError::ExecArgFailed(v),
+                    }
+                }
+                _ => PopenError::ExecArgFailed(v),
+            });
+        }
+        let r = CString::new("r").or_else(|x| Err(PopenError::CStringParesError(x)))?;
+        let w = CString::new("w").or_else(|x| Err(PopenError::CStringParesError(x)))?;
+        self.stdin = unsafe { fdopen(stdin.into_raw_fd(), w.as_ptr()) };
+        self.stdout = unsafe { fdopen(stdout.into_raw_fd(), r.as_ptr()) };
+        self.stderr = unsafe { fdopen(stderr.into_raw_fd(), r.as_ptr()) };
+        Ok(self)
+    }
+
+    // signal the child through its pidfd, so a reused pid will never be hit
+    pub fn kill(&self, signal: c_int) -> Result<(), PopenError> {
+        match &self.process {
+            Some(handle) => handle.send_signal(signal).map_err(PopenError::PidfdError),
+            None => Err(PopenError::PidfdError(PidfdError::Errno(libc::ESRCH))),
+        }
+    }
+
+    /*
+     * signal the process group lead by the child, include the grandchildren.
+     * the group id is the pid of the child, it is not reused while any member is alive
+     */
+    pub fn kill_group(&self, signal: c_int) -> Result<(), PopenError> {
+        if !self.attributes.process_group && !self.attributes.session {
+            return Err(PopenError::NoProcessGroup);
+        }
+        match self.pid {
+            Some(pid) => match unsafe { libc::killpg(pid, signal) } {
+                -1 => Err(PopenError::KillGroupFailed(unsafe { *__errno_location() })),
+                _ => Ok(()),
+            },
+            None => Err(PopenError::KillGroupFailed(libc::ESRCH)),
         }
     }
 }
@@ -168,7 +468,9 @@ impl Drop for Popen {
                 unsafe { fclose(*i) };
             }
         }
-        if let Some(pid) = self.pid {
+        if let Some(handle) = &self.process {
+            handle.wait(0).ok();
+        } else if let Some(pid) = self.pid {
             // eprintln!("pid: {}", pid);
             while {
                 match Wait::children_with(pid, 0) {
@@ -185,11 +487,14 @@ impl Drop for Popen {
 #[cfg(test)]
 mod popen {
     use libc::{
-        __errno_location, fclose, fgets, perror, socketpair, strlen, FILE, PF_UNIX, SOCK_CLOEXEC,
-        SOCK_DGRAM,
+        __errno_location, c_void, fclose, fgets, perror, pipe2, read, socketpair, strlen, write,
+        FILE, O_CLOEXEC, PF_UNIX, SOCK_CLOEXEC, SOCK_DGRAM,
     };
 
-    use crate::{popen::popen, Close, Popen, Wait};
+    use crate::{
+        popen::popen, Close, Env, Exec, ExecError, Namespaces, Popen, PopenError, SpawnBackend,
+        Wait,
+    };
 
     #[test]
     // #[ignore = "absolutely correct"]
@@ -251,10 +556,11 @@ mod popen {
     fn test_write_out() {
         let o = Popen::arg(
             r#"
-while true;
+i=0;
+while [ $i -lt 3 ];
 do
     cat /proc/stat;
-    sleep 1;
+    i=$((i + 1));
 done;
 "#,
         )
@@ -270,6 +576,354 @@ done;
         }
     }
 
+    #[test]
+    fn test_pidfd_kept() {
+        let popen = Popen::arg("date").exec().unwrap();
+        let handle = popen.process.as_ref().unwrap();
+        assert_eq!(Some(handle.pid()), popen.pid);
+        assert!(popen.kill(0).is_ok());
+    }
+
+    #[test]
+    fn test_posix_spawn_backend() {
+        let popen = Popen::arg("date")
+            .backend(SpawnBackend::PosixSpawn)
+            .exec()
+            .unwrap();
+        let handle = popen.process.as_ref().unwrap();
+        assert_eq!(Some(handle.pid()), popen.pid);
+        assert!(handle.wait(0).is_ok());
+    }
+
+    #[test]
+    fn test_pre_exec_hooks() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) }, 0);
+        let write_fd = fds[1];
+        let popen = unsafe {
+            Popen::arg("date")
+                .pre_exec(
+                    move || match write(write_fd, "1".as_ptr() as *const c_void, 1) {
+                        1 => Ok(()),
+                        _ => Err(*__errno_location()),
+                    },
+                )
+                .pre_exec(
+                    move || match write(write_fd, "2".as_ptr() as *const c_void, 1) {
+                        1 => Ok(()),
+                        _ => Err(*__errno_location()),
+                    },
+                )
+        }
+        .exec()
+        .unwrap();
+        Close::close(&[fds[1]]).unwrap();
+        let mut buf = [0u8; 4];
+        let size = unsafe { read(fds[0], buf.as_mut_ptr() as *mut c_void, 4) };
+        assert_eq!(&buf[..size as usize], b"12");
+        Close::close(&[fds[0]]).unwrap();
+        drop(popen);
+    }
+
+    #[test]
+    fn test_pre_exec_failed() {
+        let result = unsafe {
+            Popen::arg("date")
+                .pre_exec(|| Ok(()))
+                .pre_exec(|| Err(libc::EPERM))
+        }
+        .exec();
+        assert_eq!(result.err(), Some(PopenError::PreExecFailed(libc::EPERM)));
+        let result = unsafe {
+            Popen::arg("date")
+                .backend(SpawnBackend::PosixSpawn)
+                .pre_exec(|| Ok(()))
+        }
+        .exec();
+        assert!(matches!(result, Err(PopenError::BackendUnsupported(_))));
+    }
+
+    #[test]
+    fn test_chdir_failed() {
+        let result = Popen::arg("date")
+            .current_dir("/libc_tools/not/exist")
+            .exec();
+        assert_eq!(result.err(), Some(PopenError::ChdirFailed(libc::ENOENT)));
+    }
+
+    #[test]
+    fn test_current_dir() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) }, 0);
+        let write_fd = fds[1];
+        let popen = unsafe {
+            Popen::arg("date").current_dir("/").pre_exec(move || {
+                let mut buf = [0u8; 2];
+                libc::getcwd(buf.as_mut_ptr() as *mut libc::c_char, 2);
+                write(write_fd, buf.as_ptr() as *const c_void, 1);
+                Ok(())
+            })
+        }
+        .exec()
+        .unwrap();
+        Close::close(&[fds[1]]).unwrap();
+        let mut buf = [0u8; 2];
+        let size = unsafe { read(fds[0], buf.as_mut_ptr() as *mut c_void, 2) };
+        assert_eq!(&buf[..size as usize], b"/");
+        Close::close(&[fds[0]]).unwrap();
+        drop(popen);
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) }, 0);
+        let write_fd = fds[1];
+        let result = unsafe {
+            Popen::arg("date")
+                .namespaces(Namespaces::new().user().pid())
+                .pre_exec(move || {
+                    let pid = [b'0' + libc::getpid() as u8];
+                    write(write_fd, pid.as_ptr() as *const c_void, 1);
+                    Ok(())
+                })
+        }
+        .exec();
+        Close::close(&[fds[1]]).unwrap();
+        match result {
+            Ok(popen) => {
+                let mut buf = [0u8; 2];
+                let size = unsafe { read(fds[0], buf.as_mut_ptr() as *mut c_void, 2) };
+                // pid 1 is the init which reap orphans
+                assert_eq!(&buf[..size as usize], b"2");
+                drop(popen);
+            }
+            // user namespace may be disabled
+            Err(PopenError::NamespaceFailed(_, libc::EPERM)) => (),
+            Err(e) => panic!("{:?}", e),
+        }
+        Close::close(&[fds[0]]).unwrap();
+    }
+
     #[test]
     fn tty_shell() {}
+
+    // whether /proc/<pid> is gone or a zombie
+    fn exited(pid: libc::pid_t) -> bool {
+        match std::fs::read_to_string(std::format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat
+                .rsplit(')')
+                .next()
+                .unwrap()
+                .trim_start()
+                .starts_with('Z'),
+            Err(_) => true,
+        }
+    }
+
+    #[test]
+    fn test_kill_group() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) }, 0);
+        let write_fd = fds[1];
+        // the hook leave a grandchild in the group of the child
+        let popen = unsafe {
+            Popen::arg("date").process_group().pre_exec(move || {
+                let pid = match libc::fork() {
+                    -1 => return Err(*__errno_location()),
+                    // exec so the close on exec report pipe is not held by the grandchild
+                    0 => {
+                        let argv = [
+                            "sleep\0".as_ptr() as *const libc::c_char,
+                            "100\0".as_ptr() as *const libc::c_char,
+                            std::ptr::null(),
+                        ];
+                        libc::execv(
+                            "/bin/sleep\0".as_ptr() as *const libc::c_char,
+                            argv.as_ptr(),
+                        );
+                        libc::_exit(127)
+                    }
+                    v => v,
+                };
+                let group = [pid, libc::getpgid(0), libc::getpid()];
+                write(write_fd, group.as_ptr() as *const c_void, 12);
+                Ok(())
+            })
+        }
+        .exec()
+        .unwrap();
+        Close::close(&[fds[1]]).unwrap();
+        let mut group = [0 as libc::pid_t; 3];
+        let size = unsafe { read(fds[0], group.as_mut_ptr() as *mut c_void, 12) };
+        Close::close(&[fds[0]]).unwrap();
+        assert_eq!(size, 12);
+        assert_eq!(group[1], group[2]);
+        assert_eq!(Some(group[1]), popen.pid);
+        assert!(!exited(group[0]));
+        popen.kill_group(libc::SIGKILL).unwrap();
+        let mut retry = 0;
+        while !exited(group[0]) {
+            assert!(retry < 500, "grandchild still alive");
+            retry += 1;
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_kill_group_without_group() {
+        let popen = Popen::arg("date").exec().unwrap();
+        assert_eq!(popen.kill_group(0), Err(PopenError::NoProcessGroup));
+        let popen = Popen::arg("date")
+            .session()
+            .backend(SpawnBackend::PosixSpawn)
+            .exec()
+            .unwrap();
+        assert_eq!(
+            unsafe { libc::getsid(popen.pid.unwrap()) },
+            popen.pid.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parent_death_signal() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) }, 0);
+        let write_fd = fds[1];
+        let popen = unsafe {
+            Popen::arg("date")
+                .parent_death_signal(libc::SIGTERM)
+                .pre_exec(move || {
+                    let mut signal: libc::c_int = 0;
+                    libc::prctl(libc::PR_GET_PDEATHSIG, &mut signal as *mut libc::c_int);
+                    write(write_fd, &signal as *const libc::c_int as *const c_void, 4);
+                    Ok(())
+                })
+        }
+        .exec()
+        .unwrap();
+        Close::close(&[fds[1]]).unwrap();
+        let mut signal: libc::c_int = 0;
+        let size = unsafe { read(fds[0], &mut signal as *mut libc::c_int as *mut c_void, 4) };
+        Close::close(&[fds[0]]).unwrap();
+        assert_eq!((size, signal), (4, libc::SIGTERM));
+        drop(popen);
+        let result = Popen::arg("date").parent_death_signal(-1).exec();
+        assert_eq!(
+            result.err(),
+            Some(PopenError::ParentDeathSignalFailed(libc::EINVAL))
+        );
+        let result = Popen::arg("date")
+            .backend(SpawnBackend::PosixSpawn)
+            .parent_death_signal(libc::SIGTERM)
+            .exec();
+        assert!(matches!(result, Err(PopenError::BackendUnsupported(_))));
+    }
+
+    #[test]
+    fn test_exec_from_memory() {
+        let image = std::fs::read("/bin/sh").unwrap();
+        for backend in &[SpawnBackend::Fork, SpawnBackend::PosixSpawn] {
+            let program = Exec::from_memory("sh", &image[..])
+                .unwrap()
+                .args(&["-c", "echo hello"]);
+            let popen = Popen::new(program).backend(*backend).exec().unwrap();
+            let mut buf = [0 as libc::c_char; 16];
+            let p = unsafe { fgets(buf.as_mut_ptr(), 16, popen.stdout) };
+            assert!(!p.is_null());
+            let line = unsafe { std::ffi::CStr::from_ptr(p) };
+            assert_eq!(line.to_bytes(), b"hello\n");
+        }
+    }
+
+    #[test]
+    fn test_exec_from_memory_high_fd() {
+        // take the lowest 256 free fds, then the memfd is far above 3
+        let fds = (0..256)
+            .map(|_| unsafe { libc::fcntl(0, libc::F_DUPFD_CLOEXEC, 0) })
+            .collect::<Vec<_>>();
+        assert!(fds.iter().all(|x| *x >= 0));
+        let image = std::fs::read("/bin/sh").unwrap();
+        let program = Exec::from_memory("sh", &image[..])
+            .unwrap()
+            .args(&["-c", "echo hello"]);
+        Close::close(&fds[..]).unwrap();
+        // posix_spawn still find it after close_from
+        let popen = Popen::new(program)
+            .backend(SpawnBackend::PosixSpawn)
+            .exec()
+            .unwrap();
+        let mut buf = [0 as libc::c_char; 16];
+        let p = unsafe { fgets(buf.as_mut_ptr(), 16, popen.stdout) };
+        assert!(!p.is_null());
+        let line = unsafe { std::ffi::CStr::from_ptr(p) };
+        assert_eq!(line.to_bytes(), b"hello\n");
+    }
+
+    #[test]
+    fn test_env() {
+        for backend in &[SpawnBackend::Fork, SpawnBackend::PosixSpawn] {
+            let program = Exec::new("/bin/sh").args(&["-c", "echo \"$A:$LD_PRELOAD\""]);
+            let env = Env::new()
+                .set("A", "libc_tools")
+                .set("LD_PRELOAD", "x")
+                .secure();
+            let popen = Popen::new(program)
+                .env(env)
+                .backend(*backend)
+                .exec()
+                .unwrap();
+            let mut buf = [0 as libc::c_char; 32];
+            let p = unsafe { fgets(buf.as_mut_ptr(), 32, popen.stdout) };
+            assert!(!p.is_null());
+            let line = unsafe { std::ffi::CStr::from_ptr(p) };
+            assert_eq!(line.to_bytes(), b"libc_tools:\n");
+        }
+    }
+
+    // the first line of the stdout
+    fn read_line(popen: &Popen) -> Vec<u8> {
+        let mut buf = [0 as libc::c_char; 128];
+        let p = unsafe { fgets(buf.as_mut_ptr(), 128, popen.stdout) };
+        assert!(!p.is_null());
+        unsafe { std::ffi::CStr::from_ptr(p) }.to_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_no_fd_leak() {
+        // not close on exec, so it would be inherited without close_from
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        for backend in &[SpawnBackend::Fork, SpawnBackend::PosixSpawn] {
+            let popen = Popen::arg("echo $(ls /proc/self/fd)")
+                .backend(*backend)
+                .exec()
+                .unwrap();
+            // stdio and the directory opened by ls
+            assert_eq!(read_line(&popen), b"0 1 2 3\n".to_vec());
+        }
+        Close::close(&fds).unwrap();
+    }
+
+    #[test]
+    fn test_command() {
+        let args = ["%s|", "file name", "it's \"quoted\"", "$HOME", "*"];
+        let popen = Popen::command("printf", &args).exec().unwrap();
+        assert_eq!(
+            read_line(&popen),
+            b"file name|it's \"quoted\"|$HOME|*|".to_vec()
+        );
+        let result = Popen::command("libc_tools_not_exist", &[]).exec();
+        assert_eq!(
+            result.err(),
+            Some(PopenError::ExecError(ExecError::Errno(libc::ENOENT)))
+        );
+    }
+
+    #[test]
+    fn test_shell() {
+        let popen = Popen::shell("sh", "echo $((1 + 2)) \"$0\"").exec().unwrap();
+        assert_eq!(read_line(&popen), b"3 sh\n".to_vec());
+        assert_eq!(popen.arg, "echo $((1 + 2)) \"$0\"");
+    }
 }
@@ -1,11 +1,11 @@
-use std::{
-    error::Error,
-    ptr::{null, null_mut},
-};
+use std::{error::Error, ptr::null_mut};
 
-use libc::{__errno_location, _exit, c_int, execlp, forkpty, termios, winsize};
+use libc::{__errno_location, _exit, c_int, forkpty, termios, winsize};
 
-use crate::{Close, Wait};
+use crate::{
+    report::ReportPipe, Close, Exec, ExecError, ForkError, ForkOptions, PidfdError, ProcessHandle,
+    Wait,
+};
 
 /*
  * a pty terminal will have
@@ -23,23 +23,53 @@ use crate::{Close, Wait};
  * 2.wait or stop then child process id
  * 3.
  */
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Pty {
     pub pty_fd: Option<c_int>,
     pub pid: Option<c_int>,
+    pub process: Option<ProcessHandle>,
     pub device_name: Option<String>,
     pub terminal_attr: *mut termios,
     pub windows_size: *mut winsize,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum PtyError {
-    ForkFailed(c_int),
+    ForkFailed(ForkError),
     CreatePtyFailed(c_int),
+    PidfdError(PidfdError),
+    ExecFailed(ExecError),
 }
 
+// report stage of exec, stages of ForkError are below it
+const STAGE_EXEC: u32 = 32;
+
 impl Pty {
     pub fn new(terminal_attr: *mut termios, windows_size: *mut winsize) -> Result<Pty, PtyError> {
+        Self::new_with(terminal_attr, windows_size, &ForkOptions::default())
+    }
+
+    // the child set up by options before exec the shell, errors are reported as ForkFailed
+    pub fn new_with(
+        terminal_attr: *mut termios,
+        windows_size: *mut winsize,
+        options: &ForkOptions,
+    ) -> Result<Pty, PtyError> {
+        let shell = Exec::new("/bin/zsh").arg0("zsh").arg("-i");
+        Self::exec_with(terminal_attr, windows_size, options, &shell)
+    }
+
+    // run program instead of the shell, the pty is the controlling terminal and stdio of it
+    pub fn exec_with(
+        terminal_attr: *mut termios,
+        windows_size: *mut winsize,
+        options: &ForkOptions,
+        program: &Exec,
+    ) -> Result<Pty, PtyError> {
+        let program = program.prepare().map_err(PtyError::ExecFailed)?;
+        let options = options.prepare();
+        let mut report =
+            ReportPipe::new().map_err(|v| PtyError::ForkFailed(ForkError::Errno(v)))?;
         let mut pty_fd = 0;
         let mut name = [0 as u8; 50];
         let pid = unsafe {
@@ -52,21 +82,56 @@ impl Pty {
         };
         let device_name = String::from_utf8_lossy(&name[..]).to_string();
         match pid {
-            i32::MIN..=-1 => Err(PtyError::ForkFailed(unsafe { *__errno_location() })),
+            i32::MIN..=-1 => Err(PtyError::ForkFailed(ForkError::Errno(unsafe {
+                *__errno_location()
+            }))),
             0 => unsafe {
-                _exit(execlp(
-                    "/bin/zsh\0".as_ptr() as *const i8,
-                    "-i\0".as_ptr() as *const i8,
-                    null::<i8>(),
-                ))
+                report.close_read();
+                if let Err(e) = options.enter(&[report.write_fd()]) {
+                    let (stage, v) = e.to_report();
+                    report.send(stage, v);
+                    _exit(127);
+                }
+                report.send(STAGE_EXEC, program.exec());
+                _exit(127)
             },
-            _ => Ok(Pty {
-                pty_fd: Some(pty_fd),
-                device_name: Some(device_name),
-                terminal_attr,
-                windows_size,
-                pid: Some(pid),
-            }),
+            _ => {
+                // the child is not reaped yet, so the pid can not be reused before pidfd_open
+                let process = match ProcessHandle::open(pid) {
+                    Ok(handle) => handle,
+                    Err(e) => {
+                        unsafe { libc::kill(pid, libc::SIGKILL) };
+                        Close::close(&[pty_fd]).ok();
+                        Wait::children_with(pid, 0).ok();
+                        return Err(PtyError::PidfdError(e));
+                    }
+                };
+                report.close_write();
+                // eof when the child exec, or exit without report
+                if let Ok(Some((stage, v))) = report.recv() {
+                    Close::close(&[pty_fd]).ok();
+                    process.wait(0).ok();
+                    return Err(match stage {
+                        STAGE_EXEC => PtyError::ExecFailed(ExecError::Errno(v)),
+                        _ => PtyError::ForkFailed(ForkError::from_report(stage, v)),
+                    });
+                }
+                Ok(Pty {
+                    pty_fd: Some(pty_fd),
+                    device_name: Some(device_name),
+                    terminal_attr,
+                    windows_size,
+                    pid: Some(pid),
+                    process: Some(process),
+                })
+            }
+        }
+    }
+
+    pub fn kill(&self, signal: c_int) -> Result<(), PtyError> {
+        match &self.process {
+            Some(handle) => handle.send_signal(signal).map_err(PtyError::PidfdError),
+            None => Err(PtyError::PidfdError(PidfdError::Errno(libc::ESRCH))),
         }
     }
 }
@@ -74,7 +139,12 @@ impl Pty {
 impl Drop for Pty {
     fn drop(&mut self) {
         Close::close(&[self.pty_fd.unwrap()]).or_else(|x| Err(format!("{}", x))).unwrap();
-        Wait::children_with(self.pid.unwrap(), 0).or_else(|x| Err(format!("{}", x))).unwrap();
+        match &self.process {
+            Some(handle) => handle.wait(0),
+            None => Wait::children_with(self.pid.unwrap(), 0),
+        }
+        .or_else(|x| Err(format!("{}", x)))
+        .unwrap();
     }
 }
 
@@ -84,7 +154,7 @@ mod pty {
 
     use libc::{c_void, read, termios, winsize, write};
 
-    use crate::Pty;
+    use crate::{Exec, ExecError, ForkError, ForkOptions, Pty, PtyError};
 
     #[test]
     #[ignore] // cargo test this will not wait for drop, so the terminal will suspend
@@ -125,4 +195,45 @@ mod pty {
         // pty.drop()?;
         Ok(())
     }
+
+    #[test]
+    fn pty_fork_options_failed() {
+        let result = Pty::new_with(
+            null_mut::<termios>(),
+            null_mut::<winsize>(),
+            &ForkOptions::new().parent_death_signal(-1),
+        );
+        assert!(matches!(
+            result,
+            Err(PtyError::ForkFailed(ForkError::ParentDeathSignal(
+                libc::EINVAL
+            )))
+        ));
+    }
+
+    #[test]
+    fn pty_exec() {
+        let program = Exec::new("/bin/sh").args(&["-c", "test -t 0 && echo hello"]);
+        let pty = Pty::exec_with(
+            null_mut::<termios>(),
+            null_mut::<winsize>(),
+            &ForkOptions::new(),
+            &program,
+        )
+        .unwrap();
+        let mut buf = [0u8; 16];
+        let size = unsafe { read(pty.pty_fd.unwrap(), buf.as_mut_ptr() as *mut c_void, 16) };
+        assert!(size > 0);
+        assert!(buf[..size as usize].starts_with(b"hello"));
+        let result = Pty::exec_with(
+            null_mut::<termios>(),
+            null_mut::<winsize>(),
+            &ForkOptions::new(),
+            &Exec::new("/libc_tools/not/exist"),
+        );
+        assert!(matches!(
+            result,
+            Err(PtyError::ExecFailed(ExecError::Errno(libc::ENOENT)))
+        ));
+    }
 }
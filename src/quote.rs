@@ -0,0 +1,307 @@
+use std::fmt::Display;
+
+/*
+ * quote and split words of POSIX sh:
+ * quote wrap a word in single quotes unless every byte is safe, a single quote inside is
+ * written as '\'' so any byte can be quoted.
+ * split follow the token rules of sh: blanks separate words, # start a comment, backslash
+ * escape the next byte, '' keep everything, "" only escape $ ` " \ and newline.
+ * split never expand anything, so $ and ` outside single quotes, the globs * ? [ and a leading ~
+ * outside quotes, and the operators | & ; < > ( ) outside quotes are errors instead of being
+ * taken literally.
+ * = is not safe, a quoted word in command position would be an assignment otherwise
+ */
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Quote;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum QuoteError {
+    // the quote byte and its offset
+    UnterminatedQuote(u8, usize),
+    TrailingBackslash,
+    Operator(u8, usize),
+    Expansion(u8, usize),
+}
+
+impl Display for QuoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            QuoteError::UnterminatedQuote(q, i) => {
+                f.write_str(std::format!("unterminated quote {} at {}!", q as char, i).as_str())
+            }
+            QuoteError::TrailingBackslash => f.write_str("trailing backslash!"),
+            QuoteError::Operator(v, i) => f.write_str(
+                std::format!("shell operator {} at {} is not supported!", v as char, i).as_str(),
+            ),
+            QuoteError::Expansion(v, i) => f.write_str(
+                std::format!("shell expansion {} at {} is not supported!", v as char, i).as_str(),
+            ),
+        }
+    }
+}
+
+fn safe(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"_@%+:,./-".contains(&c)
+}
+
+fn blank(c: u8) -> bool {
+    c == b' ' || c == b'\t' || c == b'\n'
+}
+
+impl Quote {
+    pub fn quote(word: &[u8]) -> Vec<u8> {
+        if !word.is_empty() && word.iter().all(|c| safe(*c)) {
+            return word.to_vec();
+        }
+        let mut quoted = Vec::with_capacity(word.len() + 2);
+        quoted.push(b'\'');
+        for c in word {
+            match *c {
+                b'\'' => quoted.extend_from_slice(b"'\\''"),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push(b'\'');
+        quoted
+    }
+
+    // quote every word then join them with space
+    pub fn join(words: &[&[u8]]) -> Vec<u8> {
+        let mut joined = Vec::new();
+        for (i, word) in words.iter().enumerate() {
+            if i != 0 {
+                joined.push(b' ');
+            }
+            joined.extend(Self::quote(word));
+        }
+        joined
+    }
+
+    // quoting keep utf8 valid, so these never fail
+    pub fn quote_str(word: &str) -> String {
+        String::from_utf8(Self::quote(word.as_bytes())).unwrap()
+    }
+
+    pub fn join_str(words: &[&str]) -> String {
+        let words = words.iter().map(|x| x.as_bytes()).collect::<Vec<&[u8]>>();
+        String::from_utf8(Self::join(&words[..])).unwrap()
+    }
+
+    pub fn split(line: &[u8]) -> Result<Vec<Vec<u8>>, QuoteError> {
+        let mut words = Vec::new();
+        let mut word: Option<Vec<u8>> = None;
+        let mut i = 0;
+        while i < line.len() {
+            let c = line[i];
+            match c {
+                _ if blank(c) => {
+                    words.extend(word.take());
+                }
+                // a comment only start a word
+                b'#' if word.is_none() => {
+                    while i < line.len() && line[i] != b'\n' {
+                        i += 1;
+                    }
+                    continue;
+                }
+                b'\\' => {
+                    i += 1;
+                    match line.get(i) {
+                        None => return Err(QuoteError::TrailingBackslash),
+                        // line continuation
+                        Some(b'\n') => (),
+                        Some(v) => word.get_or_insert_with(Vec::new).push(*v),
+                    }
+                }
+                b'\'' => {
+                    let word = word.get_or_insert_with(Vec::new);
+                    let start = i;
+                    i += 1;
+                    while i < line.len() && line[i] != b'\'' {
+                        word.push(line[i]);
+                        i += 1;
+                    }
+                    if i == line.len() {
+                        return Err(QuoteError::UnterminatedQuote(b'\'', start));
+                    }
+                }
+                b'"' => {
+                    let word = word.get_or_insert_with(Vec::new);
+                    let start = i;
+                    i += 1;
+                    loop {
+                        match line.get(i) {
+                            None => return Err(QuoteError::UnterminatedQuote(b'"', start)),
+                            Some(b'"') => break,
+                            Some(b'$') | Some(b'`') => {
+                                return Err(QuoteError::Expansion(line[i], i))
+                            }
+                            Some(b'\\') => match line.get(i + 1) {
+                                Some(b'\n') => i += 1,
+                                Some(v) if b"$`\"\\".contains(v) => {
+                                    word.push(*v);
+                                    i += 1;
+                                }
+                                _ => word.push(b'\\'),
+                            },
+                            Some(v) => word.push(*v),
+                        }
+                        i += 1;
+                    }
+                }
+                b'|' | b'&' | b';' | b'<' | b'>' | b'(' | b')' => {
+                    return Err(QuoteError::Operator(c, i))
+                }
+                b'$' | b'`' | b'*' | b'?' | b'[' => return Err(QuoteError::Expansion(c, i)),
+                // tilde is only expanded at the start of a word
+                b'~' if word.is_none() => return Err(QuoteError::Expansion(c, i)),
+                _ => word.get_or_insert_with(Vec::new).push(c),
+            }
+            i += 1;
+        }
+        words.extend(word);
+        Ok(words)
+    }
+}
+
+#[cfg(test)]
+mod quote {
+    use libc::{c_void, STDOUT_FILENO};
+
+    use crate::{Exec, Fork, Quote, QuoteError};
+
+    // xorshift64, deterministic so a failure can be reproduced
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        // biased to the bytes sh treat specially, ascii without nul when not any
+        fn word(&mut self, any: bool) -> Vec<u8> {
+            const SPECIAL: &[u8] = b" \t\n'\"\\$`#|&;<>()*?[]~!{}=%";
+            let len = (self.next() % 12) as usize;
+            (0..len)
+                .map(|_| match self.next() % 4 {
+                    0 => SPECIAL[(self.next() % SPECIAL.len() as u64) as usize],
+                    1 => b'a' + (self.next() % 26) as u8,
+                    _ if any => (self.next() % 256) as u8,
+                    _ => 1 + (self.next() % 127) as u8,
+                })
+                .collect()
+        }
+
+        fn words(&mut self, any: bool) -> Vec<Vec<u8>> {
+            let len = (self.next() % 6) as usize;
+            (0..len).map(|_| self.word(any)).collect()
+        }
+    }
+
+    fn refs(words: &[Vec<u8>]) -> Vec<&[u8]> {
+        words.iter().map(|x| &x[..]).collect()
+    }
+
+    #[test]
+    fn quote_round_trip() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..2000 {
+            let word = rng.word(true);
+            assert_eq!(Quote::split(&Quote::quote(&word)), Ok(vec![word.clone()]));
+            let words = rng.words(true);
+            assert_eq!(Quote::split(&Quote::join(&refs(&words))), Ok(words));
+        }
+    }
+
+    #[test]
+    fn quote_round_trip_sh() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..32 {
+            let words = rng.words(false);
+            let mut script = b"printf '%s\\0' ".to_vec();
+            script.extend(Quote::join(&refs(&words)));
+            let script = String::from_utf8(script).unwrap();
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+            let exec = Exec::new("/bin/sh").args(&["-c", script.as_str()]);
+            let child = Fork::spawn(move || {
+                unsafe { libc::dup2(fds[1], STDOUT_FILENO) };
+                exec.exec();
+                127
+            })
+            .unwrap();
+            unsafe { libc::close(fds[1]) };
+            let mut output = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                match unsafe { libc::read(fds[0], buf.as_mut_ptr() as *mut c_void, 4096) } {
+                    -1 | 0 => break,
+                    size => output.extend_from_slice(&buf[..size as usize]),
+                }
+            }
+            unsafe { libc::close(fds[0]) };
+            child.wait().unwrap();
+            let expect = words.iter().fold(Vec::new(), |mut v, w| {
+                v.extend_from_slice(w);
+                v.push(0);
+                v
+            });
+            // printf run once with an empty argument when there is no word
+            let expect = if words.is_empty() { vec![0] } else { expect };
+            assert_eq!(output, expect, "{:?}", script);
+        }
+    }
+
+    #[test]
+    fn quote_safe_word() {
+        assert_eq!(Quote::quote(b"/bin/ls"), b"/bin/ls".to_vec());
+        assert_eq!(Quote::quote(b""), b"''".to_vec());
+        assert_eq!(Quote::quote_str("it's"), "'it'\\''s'");
+        assert_eq!(Quote::join_str(&["ls", "a b"]), "ls 'a b'");
+        assert_eq!(Quote::quote_str("a=b"), "'a=b'");
+    }
+
+    #[test]
+    fn split_sh_rules() {
+        let split = |x: &str| {
+            Quote::split(x.as_bytes()).map(|words| {
+                words
+                    .into_iter()
+                    .map(|w| String::from_utf8(w).unwrap())
+                    .collect::<Vec<String>>()
+            })
+        };
+        assert_eq!(
+            split("  ls\t-l 'a b' \"c \\\"d\\\" \\e\" f\\ g # comment\nh"),
+            Ok(vec!["ls", "-l", "a b", "c \"d\" \\e", "f g", "h"]
+                .into_iter()
+                .map(String::from)
+                .collect())
+        );
+        assert_eq!(
+            split("a#b ''"),
+            Ok(vec![String::from("a#b"), String::new()])
+        );
+        assert_eq!(split("a\\\nb"), Ok(vec![String::from("ab")]));
+        assert_eq!(split("'a"), Err(QuoteError::UnterminatedQuote(b'\'', 0)));
+        assert_eq!(split("a \"b"), Err(QuoteError::UnterminatedQuote(b'"', 2)));
+        assert_eq!(split("a\\"), Err(QuoteError::TrailingBackslash));
+        assert_eq!(split("a | b"), Err(QuoteError::Operator(b'|', 2)));
+        assert_eq!(split("\"$HOME\""), Err(QuoteError::Expansion(b'$', 1)));
+        assert_eq!(split("'$HOME'"), Ok(vec![String::from("$HOME")]));
+        assert_eq!(split("ls *.rs"), Err(QuoteError::Expansion(b'*', 3)));
+        assert_eq!(split("a?"), Err(QuoteError::Expansion(b'?', 1)));
+        assert_eq!(split("[ab]"), Err(QuoteError::Expansion(b'[', 0)));
+        assert_eq!(split("cd ~/a"), Err(QuoteError::Expansion(b'~', 3)));
+        assert_eq!(
+            split("a~ '~' \"*?[\" \\*"),
+            Ok(vec!["a~", "~", "*?[", "*"]
+                .into_iter()
+                .map(String::from)
+                .collect())
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use libc::{__errno_location, c_int, c_void, O_CLOEXEC};
+
+use crate::Close;
+
+/*
+ * a close on exec pipe used by a forked child to report (stage, value) to the parent,
+ * the parent read eof when the child exec successfully or exit without report.
+ * send do not allocate, so it is safe to use between fork and exec
+ */
+#[derive(Debug)]
+pub(crate) struct ReportPipe {
+    read: c_int,
+    write: c_int,
+}
+
+const MESSAGE_SIZE: usize = 8;
+
+impl ReportPipe {
+    pub(crate) fn new() -> Result<ReportPipe, c_int> {
+        let mut fds = [-1; 2];
+        match unsafe { libc::pipe2(fds.as_mut_ptr(), O_CLOEXEC) } {
+            -1 => Err(unsafe { *__errno_location() }),
+            _ => Ok(ReportPipe {
+                read: fds[0],
+                write: fds[1],
+            }),
+        }
+    }
+
+    pub(crate) fn write_fd(&self) -> c_int {
+        self.write
+    }
+
+    pub(crate) fn close_read(&mut self) {
+        if self.read != -1 {
+            Close::close(&[self.read]).ok();
+            self.read = -1;
+        }
+    }
+
+    pub(crate) fn close_write(&mut self) {
+        if self.write != -1 {
+            Close::close(&[self.write]).ok();
+            self.write = -1;
+        }
+    }
+
+    // child side, a message is smaller than PIPE_BUF so the write is atomic
+    pub(crate) fn send(&self, stage: u32, value: i32) {
+        let mut message = [0u8; MESSAGE_SIZE];
+        message[..4].copy_from_slice(&stage.to_ne_bytes());
+        message[4..].copy_from_slice(&value.to_ne_bytes());
+        while unsafe { libc::write(self.write, message.as_ptr() as *const c_void, MESSAGE_SIZE) }
+            == -1
+            && unsafe { *__errno_location() } == libc::EINTR
+        {}
+    }
+
+    // parent side, close the write end first or this will never see eof
+    pub(crate) fn recv(&self) -> Result<Option<(u32, i32)>, c_int> {
+        let mut message = [0u8; MESSAGE_SIZE];
+        let mut size = 0;
+        while size < MESSAGE_SIZE {
+            match unsafe {
+                libc::read(
+                    self.read,
+                    message[size..].as_mut_ptr() as *mut c_void,
+                    MESSAGE_SIZE - size,
+                )
+            } {
+                -1 => match unsafe { *__errno_location() } {
+                    libc::EINTR => continue,
+                    v => return Err(v),
+                },
+                0 => break,
+                v => size += v as usize,
+            }
+        }
+        if size < MESSAGE_SIZE {
+            return Ok(None);
+        }
+        let mut stage = [0u8; 4];
+        let mut value = [0u8; 4];
+        stage.copy_from_slice(&message[..4]);
+        value.copy_from_slice(&message[4..]);
+        Ok(Some((u32::from_ne_bytes(stage), i32::from_ne_bytes(value))))
+    }
+}
+
+impl Drop for ReportPipe {
+    fn drop(&mut self) {
+        self.close_read();
+        self.close_write();
+    }
+}
@@ -0,0 +1,338 @@
+use std::{ffi::CStr, fmt::Display, mem::MaybeUninit};
+
+use libc::{
+    c_char, c_int, c_short, c_uint, pid_t, posix_spawn_file_actions_t, posix_spawnattr_t,
+    CLOSE_RANGE_CLOEXEC,
+};
+
+use crate::{errno_name, Close, Dup, DupError};
+
+extern "C" {
+    static environ: *const *const c_char;
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum SpawnBackend {
+    // fork then apply the plan by hand in the child
+    #[default]
+    Fork,
+    // posix_spawn, libc use clone(CLONE_VM|CLONE_VFORK) so the parent memory is not copied
+    PosixSpawn,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum FileAction {
+    Dup2(c_int, c_int),
+    Close(c_int),
+    // every fd not below it
+    CloseFrom(c_int),
+}
+
+// the dup2/close plan run in the child before exec, in order
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct FileActions {
+    actions: Vec<FileAction>,
+}
+
+/*
+ * process attributes of the child, applied before the file actions:
+ * session: setsid, the child lead a new session and a new process group
+ * process_group: setpgid(0, 0), the child lead a new process group in current session
+ * so a signal send to the group reach every process the child started
+ */
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SpawnAttributes {
+    pub process_group: bool,
+    pub session: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum SpawnError {
+    Errno(c_int),
+    Dup2Errno(DupError),
+    CloseError(Close),
+    SetpgidErrno(c_int),
+    SetsidErrno(c_int),
+}
+
+impl Display for SpawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            SpawnError::Errno(v) => f.write_str(
+                std::format!("posix_spawn failed! errno: {} ({})", v, errno_name(v)).as_str(),
+            ),
+            SpawnError::Dup2Errno(v) => f.write_str(std::format!("{}", v).as_str()),
+            SpawnError::CloseError(v) => f.write_str(std::format!("{}", v).as_str()),
+            SpawnError::SetpgidErrno(v) => f.write_str(
+                std::format!("setpgid failed! errno: {} ({})", v, errno_name(v)).as_str(),
+            ),
+            SpawnError::SetsidErrno(v) => f.write_str(
+                std::format!("setsid failed! errno: {} ({})", v, errno_name(v)).as_str(),
+            ),
+        }
+    }
+}
+
+impl FileActions {
+    pub fn new() -> FileActions {
+        FileActions::default()
+    }
+
+    pub fn dup2(&mut self, old_fd: c_int, new_fd: c_int) -> &mut FileActions {
+        self.actions.push(FileAction::Dup2(old_fd, new_fd));
+        self
+    }
+
+    pub fn close(&mut self, fd: c_int) -> &mut FileActions {
+        self.actions.push(FileAction::Close(fd));
+        self
+    }
+
+    /*
+     * close every fd from fd, so fds opened by the parent without O_CLOEXEC do not leak.
+     * apply mark them close on exec instead, so the fds still work until exec
+     */
+    pub fn close_from(&mut self, fd: c_int) -> &mut FileActions {
+        self.actions.push(FileAction::CloseFrom(fd));
+        self
+    }
+
+    pub fn actions(&self) -> &[FileAction] {
+        &self.actions[..]
+    }
+
+    // run the plan in current process, used in the child of fork, do not allocate
+    pub fn apply(&self) -> Result<(), SpawnError> {
+        for action in &self.actions {
+            match *action {
+                FileAction::Dup2(old_fd, new_fd) => Dup::transform(old_fd)
+                    .close_to(new_fd)
+                    .map(|_| ())
+                    .map_err(SpawnError::Dup2Errno)?,
+                FileAction::Close(fd) => Close::close(&[fd]).map_err(SpawnError::CloseError)?,
+                FileAction::CloseFrom(fd) => {
+                    Close::close_range(fd.max(0) as c_uint, c_uint::MAX, CLOSE_RANGE_CLOEXEC)
+                        .map_err(SpawnError::CloseError)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SpawnAttributes {
+    pub fn new() -> SpawnAttributes {
+        SpawnAttributes::default()
+    }
+
+    // a new session is also a new process group, setpgid is not needed then
+    pub fn apply(&self) -> Result<(), SpawnError> {
+        if self.session {
+            if unsafe { libc::setsid() } == -1 {
+                return Err(SpawnError::SetsidErrno(unsafe {
+                    *libc::__errno_location()
+                }));
+            }
+        } else if self.process_group && unsafe { libc::setpgid(0, 0) } == -1 {
+            return Err(SpawnError::SetpgidErrno(unsafe {
+                *libc::__errno_location()
+            }));
+        }
+        Ok(())
+    }
+}
+
+// posix_spawn_file_actions_t lowered from FileActions, destroyed on drop
+struct PosixFileActions {
+    inner: posix_spawn_file_actions_t,
+}
+
+impl PosixFileActions {
+    fn new(plan: &FileActions) -> Result<PosixFileActions, SpawnError> {
+        let mut inner = MaybeUninit::<posix_spawn_file_actions_t>::uninit();
+        match unsafe { libc::posix_spawn_file_actions_init(inner.as_mut_ptr()) } {
+            0 => (),
+            v => return Err(SpawnError::Errno(v)),
+        }
+        let mut actions = PosixFileActions {
+            inner: unsafe { inner.assume_init() },
+        };
+        for action in plan.actions() {
+            let result = match *action {
+                FileAction::Dup2(old_fd, new_fd) => unsafe {
+                    libc::posix_spawn_file_actions_adddup2(&mut actions.inner, old_fd, new_fd)
+                },
+                FileAction::Close(fd) => unsafe {
+                    libc::posix_spawn_file_actions_addclose(&mut actions.inner, fd)
+                },
+                FileAction::CloseFrom(fd) => unsafe {
+                    libc::posix_spawn_file_actions_addclosefrom_np(&mut actions.inner, fd)
+                },
+            };
+            if result != 0 {
+                return Err(SpawnError::Errno(result));
+            }
+        }
+        Ok(actions)
+    }
+}
+
+impl Drop for PosixFileActions {
+    fn drop(&mut self) {
+        unsafe { libc::posix_spawn_file_actions_destroy(&mut self.inner) };
+    }
+}
+
+// posix_spawnattr_t lowered from SpawnAttributes, destroyed on drop
+struct PosixSpawnAttr {
+    inner: posix_spawnattr_t,
+}
+
+impl PosixSpawnAttr {
+    fn new(attributes: &SpawnAttributes) -> Result<PosixSpawnAttr, SpawnError> {
+        let mut inner = MaybeUninit::<posix_spawnattr_t>::uninit();
+        match unsafe { libc::posix_spawnattr_init(inner.as_mut_ptr()) } {
+            0 => (),
+            v => return Err(SpawnError::Errno(v)),
+        }
+        let mut attr = PosixSpawnAttr {
+            inner: unsafe { inner.assume_init() },
+        };
+        let flags = if attributes.session {
+            libc::POSIX_SPAWN_SETSID
+        } else if attributes.process_group {
+            // pgroup 0 means the pid of the child
+            libc::POSIX_SPAWN_SETPGROUP as c_short
+        } else {
+            0
+        };
+        match unsafe { libc::posix_spawnattr_setflags(&mut attr.inner, flags) } {
+            0 => Ok(attr),
+            v => Err(SpawnError::Errno(v)),
+        }
+    }
+}
+
+impl Drop for PosixSpawnAttr {
+    fn drop(&mut self) {
+        unsafe { libc::posix_spawnattr_destroy(&mut self.inner) };
+    }
+}
+
+pub struct Spawn;
+
+impl Spawn {
+    // argv and envp should end with null, a null envp inherit the environment of current process
+    pub fn posix_spawn(
+        path: &CStr,
+        argv: &[*const c_char],
+        envp: Option<&[*const c_char]>,
+        actions: &FileActions,
+        attributes: &SpawnAttributes,
+    ) -> Result<pid_t, SpawnError> {
+        let posix_actions = PosixFileActions::new(actions)?;
+        let posix_attr = PosixSpawnAttr::new(attributes)?;
+        let mut pid: pid_t = 0;
+        let envp = match envp {
+            Some(v) => v.as_ptr(),
+            None => unsafe { environ },
+        };
+        match unsafe {
+            libc::posix_spawn(
+                &mut pid,
+                path.as_ptr(),
+                &posix_actions.inner,
+                &posix_attr.inner,
+                argv.as_ptr() as *const *mut c_char,
+                envp as *const *mut c_char,
+            )
+        } {
+            0 => Ok(pid),
+            v => Err(SpawnError::Errno(v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod spawn {
+    use std::ffi::CString;
+
+    use libc::{c_void, STDOUT_FILENO};
+
+    use crate::{FileActions, Spawn, SpawnAttributes, Wait};
+
+    #[test]
+    fn posix_spawn_exit_code() {
+        let path = CString::new("/bin/sh").unwrap();
+        let args = ["sh", "-c", "exit 3"]
+            .iter()
+            .map(|x| CString::new(*x).unwrap())
+            .collect::<Vec<CString>>();
+        let mut argv = args.iter().map(|x| x.as_ptr()).collect::<Vec<_>>();
+        argv.push(std::ptr::null());
+        let pid = Spawn::posix_spawn(
+            &path,
+            &argv,
+            None,
+            &FileActions::new(),
+            &SpawnAttributes::new(),
+        )
+        .unwrap();
+        let (_, status) = Wait::children_with(pid, 0).unwrap();
+        assert_eq!(libc::WEXITSTATUS(status), 3);
+    }
+
+    #[test]
+    fn posix_spawn_file_actions() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        let path = CString::new("/bin/sh").unwrap();
+        let args = ["sh", "-c", "echo hello"]
+            .iter()
+            .map(|x| CString::new(*x).unwrap())
+            .collect::<Vec<CString>>();
+        let mut argv = args.iter().map(|x| x.as_ptr()).collect::<Vec<_>>();
+        argv.push(std::ptr::null());
+        let mut actions = FileActions::new();
+        actions
+            .dup2(fds[1], STDOUT_FILENO)
+            .close(fds[0])
+            .close(fds[1]);
+        let pid =
+            Spawn::posix_spawn(&path, &argv, None, &actions, &SpawnAttributes::new()).unwrap();
+        unsafe { libc::close(fds[1]) };
+        let mut buf = [0u8; 16];
+        let size = unsafe { libc::read(fds[0], buf.as_mut_ptr() as *mut c_void, 16) };
+        assert_eq!(&buf[..size as usize], b"hello\n");
+        unsafe { libc::close(fds[0]) };
+        Wait::children_with(pid, 0).unwrap();
+    }
+
+    #[test]
+    fn posix_spawn_process_group() {
+        let path = CString::new("/bin/sh").unwrap();
+        let args = ["sh", "-c", "exit 0"]
+            .iter()
+            .map(|x| CString::new(*x).unwrap())
+            .collect::<Vec<CString>>();
+        let mut argv = args.iter().map(|x| x.as_ptr()).collect::<Vec<_>>();
+        argv.push(std::ptr::null());
+        for attributes in &[
+            SpawnAttributes {
+                process_group: true,
+                session: false,
+            },
+            SpawnAttributes {
+                process_group: false,
+                session: true,
+            },
+        ] {
+            let pid =
+                Spawn::posix_spawn(&path, &argv, None, &FileActions::new(), attributes).unwrap();
+            // the zombie keep its group and session until reaped
+            assert_eq!(unsafe { libc::getpgid(pid) }, pid);
+            assert_eq!(unsafe { libc::getsid(pid) } == pid, attributes.session);
+            Wait::children_with(pid, 0).unwrap();
+        }
+    }
+}
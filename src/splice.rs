@@ -0,0 +1,258 @@
+use std::os::unix::io::AsRawFd;
+
+use libc::{__errno_location, c_int, c_uint, c_void, iovec};
+
+use crate::PipeError;
+
+/*
+ * move data between fds in the kernel:
+ * splice: one side must be a pipe, eg: child stdout to a file or socket
+ * tee: copy a pipe into another pipe without consuming it
+ * vmsplice: map user memory into a pipe
+ * when the kernel reject the fds with EINVAL, the data is copied by read and write instead.
+ * fds can be raw fds, PipeReader, PipeWriter, File or anything implementing AsRawFd
+ */
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Splice;
+
+const BUF_SIZE: usize = 65536;
+
+fn errno() -> c_int {
+    unsafe { *__errno_location() }
+}
+
+// retry on EINTR, the errno otherwise
+fn retry<F: FnMut() -> isize>(mut f: F) -> Result<usize, c_int> {
+    loop {
+        match f() {
+            -1 if errno() == libc::EINTR => continue,
+            -1 => return Err(errno()),
+            v => return Ok(v as usize),
+        }
+    }
+}
+
+fn write_all(fd: c_int, mut buf: &[u8]) -> Result<(), PipeError> {
+    while !buf.is_empty() {
+        let size = retry(|| unsafe { libc::write(fd, buf.as_ptr() as *const c_void, buf.len()) })
+            .map_err(PipeError::Errno)?;
+        buf = &buf[size..];
+    }
+    Ok(())
+}
+
+// read once then write every byte read to all of to
+fn copy(from: c_int, to: &[c_int], len: usize) -> Result<usize, PipeError> {
+    let mut buf = vec![0u8; len.min(BUF_SIZE)];
+    let size = retry(|| unsafe { libc::read(from, buf.as_mut_ptr() as *mut c_void, buf.len()) })
+        .map_err(PipeError::Errno)?;
+    for fd in to {
+        write_all(*fd, &buf[..size])?;
+    }
+    Ok(size)
+}
+
+impl Splice {
+    // move up to len bytes once, 0 means eof of from
+    pub fn splice<F: AsRawFd, T: AsRawFd>(
+        from: &F,
+        to: &T,
+        len: usize,
+        flags: c_uint,
+    ) -> Result<usize, PipeError> {
+        let (from, to) = (from.as_raw_fd(), to.as_raw_fd());
+        match retry(|| unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                len,
+                flags,
+            )
+        }) {
+            Err(libc::EINVAL) => copy(from, &[to], len),
+            result => result.map_err(PipeError::Errno),
+        }
+    }
+
+    // move everything until eof of from, return the bytes moved
+    pub fn splice_all<F: AsRawFd, T: AsRawFd>(from: &F, to: &T) -> Result<usize, PipeError> {
+        let mut total = 0;
+        loop {
+            match Self::splice(from, to, BUF_SIZE, libc::SPLICE_F_MOVE)? {
+                0 => return Ok(total),
+                v => total += v,
+            }
+        }
+    }
+
+    // both are pipes, the data stay in from. no fallback as read would consume from
+    pub fn tee<F: AsRawFd, T: AsRawFd>(
+        from: &F,
+        to: &T,
+        len: usize,
+        flags: c_uint,
+    ) -> Result<usize, PipeError> {
+        let (from, to) = (from.as_raw_fd(), to.as_raw_fd());
+        retry(|| unsafe { libc::tee(from, to, len, flags) as isize }).map_err(PipeError::Errno)
+    }
+
+    /*
+     * copy everything until eof of from to both first and second:
+     * tee into first then splice the same bytes into second.
+     * on EINVAL, eg: from is not a pipe, read once and write to both
+     */
+    pub fn tee_all<F: AsRawFd, A: AsRawFd, B: AsRawFd>(
+        from: &F,
+        first: &A,
+        second: &B,
+    ) -> Result<usize, PipeError> {
+        let (from, first, second) = (from.as_raw_fd(), first.as_raw_fd(), second.as_raw_fd());
+        let mut total = 0;
+        loop {
+            let size = match retry(|| unsafe { libc::tee(from, first, BUF_SIZE, 0) as isize }) {
+                Err(libc::EINVAL) => copy(from, &[first, second], BUF_SIZE)?,
+                Err(v) => return Err(PipeError::Errno(v)),
+                Ok(0) => 0,
+                Ok(size) => {
+                    let mut left = size;
+                    while left != 0 {
+                        left -= Self::splice(&from, &second, left, libc::SPLICE_F_MOVE)?;
+                    }
+                    size
+                }
+            };
+            if size == 0 {
+                return Ok(total);
+            }
+            total += size;
+        }
+    }
+
+    /// map data into the pipe to, write it instead on EINVAL
+    ///
+    /// # Safety
+    /// the pages of data are referenced by the pipe until the reader consume them,
+    /// so data should not be changed or freed before that, unless SPLICE_F_GIFT is used
+    pub unsafe fn vmsplice<T: AsRawFd>(
+        to: &T,
+        data: &[u8],
+        flags: c_uint,
+    ) -> Result<usize, PipeError> {
+        let to = to.as_raw_fd();
+        let iov = iovec {
+            iov_base: data.as_ptr() as *mut c_void,
+            iov_len: data.len(),
+        };
+        match retry(|| libc::vmsplice(to, &iov, 1, flags)) {
+            Err(libc::EINVAL) => {
+                retry(|| libc::write(to, data.as_ptr() as *const c_void, data.len()))
+                    .map_err(PipeError::Errno)
+            }
+            result => result.map_err(PipeError::Errno),
+        }
+    }
+}
+
+#[cfg(test)]
+mod splice {
+    use std::{
+        fs,
+        io::{Read, Seek, SeekFrom, Write},
+    };
+
+    use crate::{temp_path, Pipe, Splice};
+
+    fn temp_file(name: &str) -> (fs::File, String) {
+        let path = temp_path(name);
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        (file, path)
+    }
+
+    #[test]
+    fn splice_pipe_to_file() {
+        let (reader, mut writer) = Pipe::pipe().unwrap().split();
+        let data = (0..100000).map(|x| x as u8).collect::<Vec<u8>>();
+        let (mut file, path) = temp_file("splice_pipe_to_file");
+        let child = std::thread::spawn(move || writer.write_all(&data).unwrap());
+        assert_eq!(Splice::splice_all(&reader, &file).unwrap(), 100000);
+        child.join().unwrap();
+        let mut content = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content, (0..100000).map(|x| x as u8).collect::<Vec<u8>>());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn splice_fallback() {
+        // neither is a pipe, splice fail with EINVAL
+        let (mut from, from_path) = temp_file("splice_fallback_from");
+        let (mut to, to_path) = temp_file("splice_fallback_to");
+        from.write_all(b"hello").unwrap();
+        from.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(Splice::splice_all(&from, &to).unwrap(), 5);
+        let mut content = String::new();
+        to.seek(SeekFrom::Start(0)).unwrap();
+        to.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello");
+        fs::remove_file(from_path).unwrap();
+        fs::remove_file(to_path).unwrap();
+    }
+
+    #[test]
+    fn tee_two_consumers() {
+        let (reader, mut writer) = Pipe::pipe().unwrap().split();
+        let (mut first, first_writer) = Pipe::pipe().unwrap().split();
+        let (mut second, second_writer) = Pipe::pipe().unwrap().split();
+        writer.write_all(b"hello").unwrap();
+        assert_eq!(Splice::tee(&reader, &first_writer, 5, 0), Ok(5));
+        assert_eq!(Splice::splice(&reader, &second_writer, 5, 0), Ok(5));
+        writer.write_all(b" world").unwrap();
+        drop(writer);
+        assert_eq!(
+            Splice::tee_all(&reader, &first_writer, &second_writer),
+            Ok(6)
+        );
+        drop((first_writer, second_writer));
+        for consumer in [&mut first, &mut second].iter_mut() {
+            let mut content = String::new();
+            consumer.read_to_string(&mut content).unwrap();
+            assert_eq!(content, "hello world");
+        }
+    }
+
+    #[test]
+    fn tee_fallback() {
+        let (mut from, path) = temp_file("tee_fallback");
+        from.write_all(b"hello").unwrap();
+        from.seek(SeekFrom::Start(0)).unwrap();
+        let (mut first, first_writer) = Pipe::pipe().unwrap().split();
+        let (mut second, second_writer) = Pipe::pipe().unwrap().split();
+        assert_eq!(Splice::tee_all(&from, &first_writer, &second_writer), Ok(5));
+        drop((first_writer, second_writer));
+        let mut content = String::new();
+        first.read_to_string(&mut content).unwrap();
+        second.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hellohello");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn vmsplice_pipe() {
+        let (mut reader, writer) = Pipe::pipe().unwrap().split();
+        let data = b"hello".to_vec();
+        assert_eq!(unsafe { Splice::vmsplice(&writer, &data, 0) }, Ok(5));
+        let mut buf = [0u8; 5];
+        reader.read_exact(&mut buf).unwrap();
+        drop(data);
+        assert_eq!(&buf, b"hello");
+    }
+}
@@ -47,4 +47,34 @@ impl Wait {
             }
         }
     }
+
+    // wait through a pidfd, the status is encoded as waitpid does, so WIFEXITED and friends work
+    pub fn pidfd(
+        pidfd: libc::c_int,
+        options: libc::c_int,
+    ) -> Result<(libc::pid_t, libc::c_int), Wait> {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let result = unsafe {
+            libc::waitid(
+                libc::P_PIDFD,
+                pidfd as libc::id_t,
+                &mut info as *mut libc::siginfo_t,
+                libc::WEXITED | options,
+            )
+        };
+        match result {
+            -1 => Err(Wait::WaitFailure(unsafe { *libc::__errno_location() })),
+            _ => {
+                let (pid, status) = unsafe { (info.si_pid(), info.si_status()) };
+                match info.si_code {
+                    _ if pid == 0 && options & libc::WNOHANG != 0 => Err(Wait::WNoHangExit),
+                    libc::CLD_EXITED => Ok((pid, (status & 0xff) << 8)),
+                    libc::CLD_KILLED => Ok((pid, status)),
+                    libc::CLD_DUMPED => Ok((pid, status | 0x80)),
+                    libc::CLD_CONTINUED => Ok((pid, 0xffff)),
+                    _ => Ok((pid, (status << 8) | 0x7f)),
+                }
+            }
+        }
+    }
 }